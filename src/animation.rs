@@ -1,77 +1,133 @@
-use na::Matrix4;
+use na::{Isometry3, Matrix4, Quaternion, Translation3, Unit, UnitQuaternion, Vector3};
 use std::collections::HashMap;
 
 use super::transform::Transform;
 use serde::{Deserialize, Serialize};
+
+/// Offset under which a joint is considered not animated
+const STATIC_EPSILON: f32 = 1e-5;
+
+/// The local transforms of a joint sampled at each frame of an animation
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct Keyframe {
-    // The transform matrix of each bone per each keyframe
-    //pub bone_transforms: HashMap<String, Transform>,
-    //pub bone_transforms: HashMap<String, Transform>,
-    pub transforms: Vec<Matrix4<f32>>,
-    // Times when each keyframe begins. Of size Nkeyframe
-    pub start_time: f32,
+pub struct Track {
+    translations: Vec<Vector3<f32>>,
+    rotations: Vec<UnitQuaternion<f32>>,
+    scales: Vec<Vector3<f32>>,
 }
 
-impl PartialEq for Keyframe {
-    fn eq(&self, other: &Self) -> bool {
-        self.start_time == other.start_time
+impl Track {
+    fn new() -> Self {
+        Track {
+            translations: vec![],
+            rotations: vec![],
+            scales: vec![],
+        }
     }
-}
 
-impl Keyframe {
-    fn new(
-        skeleton: &Skeleton,
-        bone_animations: &[collada::Animation],
-        start_time: f32,
-        idx_keyframe: usize,
-        alpha: f32,
-    ) -> Self {
-        let mut local_transforms = HashMap::with_capacity(bone_animations.len());
-        let global_inverse_transform = Matrix4::identity();
-
-        for collada::Animation {
-            target,
-            sample_poses,
-            ..
-        } in bone_animations.iter()
-        {
-            let bone_name = target.split('/').collect::<Vec<_>>();
-            let bone_name = bone_name[0];
+    fn push(&mut self, transform: &Transform) {
+        self.translations.push(*transform.get_translation());
+        self.rotations.push(*transform.get_rotation());
+        self.scales.push(*transform.get_scale());
+    }
 
-            let t0: Transform = (&sample_poses[idx_keyframe - 1]).into();
-            let t1: Transform = (&sample_poses[idx_keyframe]).into();
+    /// The local transform of the joint at a frame
+    pub fn get(&self, frame_idx: usize) -> Transform {
+        Transform::from_parts(
+            self.translations[frame_idx],
+            self.rotations[frame_idx],
+            self.scales[frame_idx],
+        )
+    }
 
-            let t = t0.interpolate(&t1, alpha).into();
+    pub fn get_translations(&self) -> &[Vector3<f32>] {
+        &self.translations
+    }
 
-            local_transforms.insert(bone_name.to_string(), t);
-        }
+    pub fn get_rotations(&self) -> &[UnitQuaternion<f32>] {
+        &self.rotations
+    }
 
-        let transforms =
-            compute_final_transforms(skeleton, &local_transforms, &global_inverse_transform);
-        //unreachable!();
-        Keyframe {
-            transforms,
-            start_time,
-        }
+    pub fn get_scales(&self) -> &[Vector3<f32>] {
+        &self.scales
     }
 }
 
-use std::cmp::{Ordering, PartialOrd};
+/// Interpolate the local transforms of the joints between two samples
+///
+/// The joints without any animation keep their rest pose
+fn sample_local_transforms(
+    bone_animations: &[collada::Animation],
+    channels: &[Option<usize>],
+    rest_local_transforms: &[Transform],
+    idx_keyframe: usize,
+    alpha: f32,
+) -> Vec<Transform> {
+    channels
+        .iter()
+        .zip(rest_local_transforms.iter())
+        .map(|(channel, rest_local_transform)| {
+            if let Some(channel) = channel {
+                let sample_poses = &bone_animations[*channel].sample_poses;
 
-impl PartialOrd for Keyframe {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.start_time.partial_cmp(&other.start_time)
-    }
+                let t0: Transform = (&sample_poses[idx_keyframe - 1]).into();
+                let t1: Transform = (&sample_poses[idx_keyframe]).into();
+
+                t0.interpolate(&t1, alpha)
+            } else {
+                rest_local_transform.clone()
+            }
+        })
+        .collect()
+}
+
+/// How a clip is played outside of its duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WrapMode {
+    /// Play the clip once then go back to its first frame
+    Once,
+    /// Restart the clip from its beginning
+    Loop,
+    /// Play the clip forward then backward
+    PingPong,
+    /// Hold the last frame
+    ClampForever,
+}
+
+/// A named event at a time of a clip, e.g. a footstep
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnimationEvent {
+    pub name: String,
+    pub time: f32,
+}
+
+/// The pose an additive clip is made relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdditiveReference {
+    /// The first frame of the clip itself, e.g. for breathing
+    FirstFrame,
+    /// The rest pose of the skeleton
+    BindPose,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Animation {
     /// The duration of an animation
     duration: f32,
-    // A sorted-by-time keyframe vector
-    keys: Vec<Keyframe>,
+    // The sorted start times of the sampled frames
+    times: Vec<f32>,
+    // The local transforms of each joint, indexed by joint index
+    tracks: Vec<Track>,
+    // The skinning matrices of each frame, only computed
+    // when the animation is baked
+    baked: Option<Vec<Vec<Matrix4<f32>>>>,
     frame_time: f32,
+    wrap_mode: WrapMode,
+    // Whether the tracks store offsets relative to a reference pose
+    additive: bool,
+    // Sorted by time
+    events: Vec<AnimationEvent>,
+    // The model space motion extracted from the root bone at each frame
+    root_motion: Option<Vec<Isometry3<f32>>>,
 }
 impl Animation {
     pub fn new(
@@ -86,21 +142,44 @@ impl Animation {
             let end_time = *first_anim_j.sample_times.last().unwrap();
             end_time - start_time
         };
-        //let final_transforms = Vec::with_capacity(bone_animations.len());
 
         // At least two keyframes
         let mut idx_keyframe = 1;
         let first_bone_animation = &bone_animations[0];
         let mut end_time_keyframe = first_bone_animation.sample_times[1];
 
-        //let duration = (num_frames as f32) * FRAME_TIME;
-        let mut keys = Vec::new();
+        // Index of the animation targeting each joint
+        let channels = skeleton
+            .get_joint_names()
+            .iter()
+            .map(|name| {
+                bone_animations.iter().position(|anim| {
+                    let bone_name = anim.target.split('/').next().unwrap();
+                    bone_name == name
+                })
+            })
+            .collect::<Vec<_>>();
+        let rest_local_transforms = skeleton
+            .get_rest_local_transforms()
+            .iter()
+            .map(|m| m.into())
+            .collect::<Vec<Transform>>();
+
+        let mut times = Vec::new();
+        let mut tracks = vec![Track::new(); channels.len()];
+        let mut push_frame = |time: f32, local_transforms: Vec<Transform>| {
+            times.push(time);
+            for (track, local_transform) in tracks.iter_mut().zip(local_transforms.iter()) {
+                track.push(local_transform);
+            }
+        };
 
-        //let mut frame_idx = 0;
         let mut time = 0.0;
 
+        let num_samples = first_bone_animation.sample_times.len();
         while time < duration {
-            if time >= end_time_keyframe {
+            // The samples can be closer than the frame time
+            while time >= end_time_keyframe && idx_keyframe + 1 < num_samples {
                 idx_keyframe += 1;
                 end_time_keyframe = bone_animations[0].sample_times[idx_keyframe];
             }
@@ -113,24 +192,41 @@ impl Animation {
                 0.0
             };
 
-            let keyframe = Keyframe::new(skeleton, &bone_animations, time, idx_keyframe, alpha);
-            keys.push(keyframe);
+            push_frame(
+                time,
+                sample_local_transforms(
+                    &bone_animations,
+                    &channels,
+                    &rest_local_transforms,
+                    idx_keyframe,
+                    alpha,
+                ),
+            );
 
             time += frame_time;
         }
 
-        keys.push(Keyframe::new(
-            skeleton,
-            &bone_animations,
+        push_frame(
             duration,
-            idx_keyframe,
-            1.0,
-        ));
+            sample_local_transforms(
+                &bone_animations,
+                &channels,
+                &rest_local_transforms,
+                num_samples - 1,
+                1.0,
+            ),
+        );
 
         Animation {
             duration,
-            keys,
+            times,
+            tracks,
+            baked: None,
             frame_time,
+            wrap_mode: WrapMode::ClampForever,
+            additive: false,
+            events: vec![],
+            root_motion: None,
         }
     }
 
@@ -170,76 +266,613 @@ impl Animation {
         }
     }*/
 
-    pub fn query(&self, time: f32) -> &Vec<Matrix4<f32>> {
-        let key = if time <= 0.0 {
-            &self.keys[0]
+    /// Map a time onto the clip according to its wrap mode
+    pub fn wrap_time(&self, time: f32) -> f32 {
+        if self.duration <= 0.0 {
+            return 0.0;
+        }
+
+        match self.wrap_mode {
+            WrapMode::Once => {
+                if time >= self.duration {
+                    // Back to the start once played
+                    0.0
+                } else {
+                    time.max(0.0)
+                }
+            }
+            WrapMode::Loop => time.rem_euclid(self.duration),
+            WrapMode::PingPong => {
+                let time = time.rem_euclid(2.0 * self.duration);
+                if time > self.duration {
+                    2.0 * self.duration - time
+                } else {
+                    time
+                }
+            }
+            WrapMode::ClampForever => time.clamp(0.0, self.duration),
+        }
+    }
+
+    /// The position of a time in the clip between 0.0 (start) and 1.0 (end)
+    pub fn normalized_time(&self, time: f32) -> f32 {
+        if self.duration > 0.0 {
+            self.wrap_time(time) / self.duration
+        } else {
+            0.0
+        }
+    }
+
+    /// The frame starting before a time, the frame after it
+    /// and the interpolation factor between the two
+    fn get_frames(&self, time: f32) -> (usize, usize, f32) {
+        let time = self.wrap_time(time);
+
+        let last_frame_idx = self.times.len() - 1;
+        if time <= 0.0 || last_frame_idx == 0 {
+            (0, 0, 0.0)
         } else if time >= self.duration {
-            &self.keys.last().unwrap()
+            (last_frame_idx, last_frame_idx, 0.0)
+        } else {
+            let frame_idx = ((time / self.frame_time) as usize).min(last_frame_idx - 1);
+            let mut next_frame_idx = frame_idx + 1;
+            if next_frame_idx == last_frame_idx && self.wrap_mode == WrapMode::Loop {
+                // The end of a looping clip is its start
+                next_frame_idx = 0;
+            }
+
+            let t0 = self.times[frame_idx];
+            let t1 = self.times[frame_idx + 1];
+            let alpha = if t1 > t0 {
+                ((time - t0) / (t1 - t0)).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+
+            (frame_idx, next_frame_idx, alpha)
+        }
+    }
+
+    /// The local transform of a joint between two frames
+    fn get_local_transform(&self, joint_idx: usize, frames: (usize, usize, f32)) -> Transform {
+        let (frame_idx, next_frame_idx, alpha) = frames;
+        let track = &self.tracks[joint_idx];
+        if alpha > 0.0 {
+            track
+                .get(frame_idx)
+                .interpolate(&track.get(next_frame_idx), alpha)
         } else {
-            let frame_idx = (time / self.frame_time) as usize;
-            &self.keys[frame_idx]
+            track.get(frame_idx)
+        }
+    }
+
+    /// The local transforms of the joints at a frame, indexed by joint index
+    fn get_frame_local_transforms(&self, frame_idx: usize) -> Vec<Transform> {
+        self.tracks
+            .iter()
+            .map(|track| track.get(frame_idx))
+            .collect()
+    }
+
+    /// The local transforms of the joints at a specific time, indexed by joint index
+    fn interpolate_local_transforms(&self, time: f32) -> Vec<Transform> {
+        let frames = self.get_frames(time);
+        (0..self.tracks.len())
+            .map(|joint_idx| self.get_local_transform(joint_idx, frames))
+            .collect()
+    }
+
+    /// Compute the skinning matrices of all the frames once
+    ///
+    /// The queries at the time of a frame then return the stored matrices instead
+    /// of computing them from the local transforms. In between two frames, the local
+    /// transforms are still interpolated: interpolating the skinning matrices
+    /// themselves would move the vertices away from their exact positions.
+    /// This is faster when the clip is played at its sampling rate but the
+    /// stored matrices take much more memory.
+    pub fn bake(&mut self, skeleton: &Skeleton) {
+        let baked = (0..self.times.len())
+            .map(|frame_idx| {
+                Pose::new(self.get_frame_local_transforms(frame_idx)).skinning_transforms(skeleton)
+            })
+            .collect();
+        self.baked = Some(baked);
+    }
+
+    pub fn is_baked(&self) -> bool {
+        self.baked.is_some()
+    }
+
+    /// Set how the clip is played outside of its duration,
+    /// the clips hold their last frame by default
+    pub fn set_wrap_mode(&mut self, wrap_mode: WrapMode) {
+        self.wrap_mode = wrap_mode;
+    }
+
+    pub fn get_wrap_mode(&self) -> WrapMode {
+        self.wrap_mode
+    }
+
+    /// Convert the clip into an additive clip relative to a reference pose
+    ///
+    /// The sampled poses of the clip then are offsets to add on top of
+    /// another pose with `Pose::add`, rather than poses to play on their own
+    pub fn make_additive(&mut self, skeleton: &Skeleton, reference: AdditiveReference) {
+        if self.additive {
+            return;
+        }
+
+        let reference_transforms = match reference {
+            AdditiveReference::FirstFrame => self.get_frame_local_transforms(0),
+            AdditiveReference::BindPose => Pose::rest(skeleton).get_local_transforms().to_vec(),
         };
+        for (track, reference_transform) in self.tracks.iter_mut().zip(reference_transforms.iter())
+        {
+            let mut additive_track = Track::new();
+            for frame_idx in 0..self.times.len() {
+                additive_track.push(&track.get(frame_idx).difference(reference_transform));
+            }
+            *track = additive_track;
+        }
+        self.additive = true;
+
+        // The baked matrices of an additive clip would not make sense
+        self.baked = None;
+    }
+
+    pub fn is_additive(&self) -> bool {
+        self.additive
+    }
+
+    /// Attach a named event at a time of the clip
+    ///
+    /// The time must be between 0 and the duration of the clip.
+    pub fn add_event(&mut self, name: &str, time: f32) -> Result<(), Error> {
+        if !(0.0..=self.duration).contains(&time) {
+            return Err(Error::InvalidEventTime { time });
+        }
+
+        let idx = self.events.partition_point(|event| event.time <= time);
+        self.events.insert(
+            idx,
+            AnimationEvent {
+                name: name.to_owned(),
+                time,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// The events of the clip, sorted by time
+    pub fn get_events(&self) -> &[AnimationEvent] {
+        &self.events
+    }
+
+    /// The events crossed when playing from `previous_time`
+    /// (included) to `time` (excluded), in the order they are crossed
+    ///
+    /// The times are playback times as given to `query`, so a looping clip
+    /// reports its events once per cycle and a ping-pong clip reports them
+    /// backward on its way back. A clip played once or clamped reports its
+    /// remaining events when `time` reaches its end.
+    pub fn events_between(&self, previous_time: f32, time: f32) -> Vec<&AnimationEvent> {
+        let mut events = vec![];
+        if time <= previous_time || self.duration <= 0.0 {
+            return events;
+        }
+
+        let cycles = match self.wrap_mode {
+            WrapMode::Loop | WrapMode::PingPong => {
+                let first_cycle = (previous_time / self.duration).floor() as i64;
+                let last_cycle = (time / self.duration).floor() as i64;
+                first_cycle..=last_cycle
+            }
+            WrapMode::Once | WrapMode::ClampForever => 0..=0,
+        };
+        for cycle in cycles {
+            let offset = cycle as f32 * self.duration;
+            let start = (previous_time - offset).max(0.0);
+            let end = time - offset;
+            let end_reached = end >= self.duration;
+            let end = end.min(self.duration);
+            if start >= end && !end_reached {
+                continue;
+            }
+
+            if self.wrap_mode == WrapMode::PingPong && cycle % 2 != 0 {
+                // The clip is played backward from its end
+                let (start, end) = (self.duration - end, self.duration - start);
+                events.extend(
+                    self.events
+                        .iter()
+                        .rev()
+                        .filter(|event| event.time > start && event.time <= end),
+                );
+            } else {
+                // The events at the very end of a clip that does not loop are
+                // reported as it ends, those of a looping clip at the start
+                // of the next cycle
+                let previous_end_crossed = self.wrap_mode == WrapMode::Loop
+                    && (previous_time < offset || (previous_time == offset && cycle > 0));
+                if previous_end_crossed {
+                    events.extend(
+                        self.events
+                            .iter()
+                            .filter(|event| event.time >= self.duration),
+                    );
+                }
+                let clamped = matches!(self.wrap_mode, WrapMode::Once | WrapMode::ClampForever);
+                events.extend(self.events.iter().filter(|event| {
+                    let ends = clamped && end_reached && start < self.duration;
+                    event.time >= start && (event.time < end || ends)
+                }));
+            }
+        }
+
+        events
+    }
+
+    /// Move the horizontal translation and the yaw of a root bone into a root motion track
+    ///
+    /// The motion is measured from the first frame in model space, `up` being
+    /// the up axis of the model (e.g. Z for a Z_UP COLLADA file). The bone then
+    /// stays in place horizontally and gameplay moves the character with
+    /// `query_root_motion`. The root motion can only be extracted once.
+    pub fn extract_root_motion(
+        &mut self,
+        skeleton: &Skeleton,
+        id: BoneId,
+        up: &Unit<Vector3<f32>>,
+    ) -> Result<(), Error> {
+        if self.root_motion.is_some() {
+            return Err(Error::RootMotionAlreadyExtracted);
+        }
+        if self.times.is_empty() {
+            // A clip without frames does not move
+            self.root_motion = Some(vec![]);
+            return Ok(());
+        }
+
+        let horizontal = |v: Vector3<f32>| v - up.into_inner() * v.dot(up);
+
+        // The model space transforms of the parent and of the bone at each frame
+        let frames = (0..self.times.len())
+            .map(|frame_idx| {
+                let parent_transform =
+                    skeleton
+                        .ancestors(id)
+                        .fold(Matrix4::identity(), |transform, ancestor| {
+                            let local_transform: Matrix4<f32> =
+                                self.tracks[ancestor.0].get(frame_idx).into();
+                            local_transform * transform
+                        });
+                let local_transform: Matrix4<f32> = self.tracks[id.0].get(frame_idx).into();
+                (parent_transform, parent_transform * local_transform)
+            })
+            .collect::<Vec<_>>();
+
+        let first: Transform = (&frames[0].1).into();
+        let pivot = Translation3::from(horizontal(*first.get_translation()));
+        let root_motion = frames
+            .iter()
+            .map(|(_, model_transform)| {
+                let model_transform: Transform = model_transform.into();
+                let offset =
+                    horizontal(model_transform.get_translation() - first.get_translation());
+                let yaw = twist(
+                    &(model_transform.get_rotation() * first.get_rotation().inverse()),
+                    up,
+                );
+
+                // Turn around the initial position of the bone
+                Isometry3::from_parts(Translation3::from(offset) * pivot, yaw) * pivot.inverse()
+            })
+            .collect::<Vec<_>>();
+
+        let mut track = Track::new();
+        for ((parent_transform, model_transform), root_transform) in
+            frames.iter().zip(root_motion.iter())
+        {
+            let parent_inverse = parent_transform
+                .try_inverse()
+                .unwrap_or_else(Matrix4::identity);
+            let local_transform =
+                parent_inverse * root_transform.inverse().to_homogeneous() * model_transform;
+            track.push(&(&local_transform).into());
+        }
+        self.tracks[id.0] = track;
+        self.root_motion = Some(root_motion);
+
+        if self.is_baked() {
+            self.bake(skeleton);
+        }
+
+        Ok(())
+    }
+
+    /// The root motion at each frame, None if it has not been extracted
+    pub fn get_root_motion(&self) -> Option<&[Isometry3<f32>]> {
+        self.root_motion.as_deref()
+    }
+
+    /// The motion of the character between two playback times,
+    /// in the space of the character at `previous_time`
+    ///
+    /// The motion accumulates over the cycles of a looping clip.
+    /// The identity if no root motion has been extracted.
+    pub fn query_root_motion(&self, previous_time: f32, time: f32) -> Isometry3<f32> {
+        if let Some(root_motion) = &self.root_motion {
+            let previous = self.root_transform(root_motion, previous_time);
+            previous.inverse() * self.root_transform(root_motion, time)
+        } else {
+            Isometry3::identity()
+        }
+    }
+
+    /// The root motion at a playback time
+    fn root_transform(&self, root_motion: &[Isometry3<f32>], time: f32) -> Isometry3<f32> {
+        let last_frame_idx = match root_motion.len() {
+            0 => return Isometry3::identity(),
+            1 => return root_motion[0],
+            len => len - 1,
+        };
+        if self.duration <= 0.0 {
+            return root_motion[0];
+        }
+
+        let (cycles, time) = if self.wrap_mode == WrapMode::Loop {
+            (
+                (time / self.duration).floor() as i32,
+                time.rem_euclid(self.duration),
+            )
+        } else {
+            (0, self.wrap_time(time))
+        };
+
+        let frame_idx = ((time / self.frame_time) as usize).min(last_frame_idx - 1);
+        let (t0, t1) = (self.times[frame_idx], self.times[frame_idx + 1]);
+        let alpha = if t1 > t0 {
+            ((time - t0) / (t1 - t0)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let transform = root_motion[frame_idx].lerp_slerp(&root_motion[frame_idx + 1], alpha);
+
+        // Each full cycle of a looping clip adds the motion of the whole clip
+        let cycle = if cycles >= 0 {
+            root_motion[last_frame_idx]
+        } else {
+            root_motion[last_frame_idx].inverse()
+        };
+        (0..cycles.abs()).fold(transform, |transform, _| cycle * transform)
+    }
+
+    /// Query the skinning matrices at a specific time, indexed by skinning index
+    ///
+    /// The local transforms of the two neighboring frames are interpolated, linearly
+    /// for the translations and with slerp for the rotations. The matrices are written into `transforms`
+    /// so that the buffer can be reused from one frame to the next.
+    pub fn query(&self, skeleton: &Skeleton, time: f32, transforms: &mut Vec<Matrix4<f32>>) {
+        let frames = self.get_frames(time);
+        if let (Some(baked), (frame_idx, _, alpha)) = (&self.baked, frames) {
+            if alpha <= 0.0 {
+                transforms.clear();
+                transforms.extend_from_slice(&baked[frame_idx]);
+                return;
+            }
+        }
+
+        let local_transforms = (0..self.tracks.len())
+            .map(|joint_idx| self.get_local_transform(joint_idx, frames).into())
+            .collect::<Vec<_>>();
+        compute_final_transforms(
+            skeleton,
+            &local_transforms,
+            &Matrix4::identity(),
+            transforms,
+        );
+    }
+
+    /// Query the skinning matrices at a normalized time,
+    /// 0.0 being the start of the clip and 1.0 its end
+    pub fn query_normalized(
+        &self,
+        skeleton: &Skeleton,
+        normalized_time: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) {
+        self.query(skeleton, normalized_time * self.duration, transforms)
+    }
 
-        &key.transforms
+    /// Query the model space transforms of all the joints, indexed by joint index
+    ///
+    /// Contrary to `query`, the inverse bind poses are not applied and the
+    /// joints without vertices attached (e.g. helper or socket bones) are returned.
+    pub fn query_model_pose(&self, skeleton: &Skeleton, time: f32) -> Vec<Matrix4<f32>> {
+        self.query_pose(time).model_transforms(skeleton)
+    }
+
+    /// Query the local transforms of the joints as a pose that can be edited
+    pub fn query_pose(&self, time: f32) -> Pose {
+        Pose::new(self.interpolate_local_transforms(time))
+    }
+
+    /// Query the model space transform of one joint
+    pub fn query_bone_transform(&self, skeleton: &Skeleton, time: f32, id: BoneId) -> Matrix4<f32> {
+        let frames = self.get_frames(time);
+
+        // Only the transforms from the root down to the bone are needed
+        std::iter::once(id).chain(skeleton.ancestors(id)).fold(
+            Matrix4::identity(),
+            |transform, id| {
+                let local_transform: Matrix4<f32> = self.get_local_transform(id.0, frames).into();
+                local_transform * transform
+            },
+        )
     }
 
     pub fn get_duration(&self) -> f32 {
         self.duration
     }
+
+    pub fn get_frame_time(&self) -> f32 {
+        self.frame_time
+    }
+
+    /// The start times of the sampled frames
+    pub fn get_times(&self) -> &[f32] {
+        &self.times
+    }
+
+    /// The sampled local transforms of a joint
+    pub fn get_track(&self, id: BoneId) -> &Track {
+        &self.tracks[id.0]
+    }
+
+    /// Whether the local transform of a joint moves away from its rest transform
+    pub(crate) fn is_joint_animated(&self, idx: usize, rest_local_transform: &Transform) -> bool {
+        let track = &self.tracks[idx];
+        (0..self.times.len()).any(|frame_idx| {
+            let local_transform = track.get(frame_idx);
+            let translation_offset =
+                (local_transform.get_translation() - rest_local_transform.get_translation()).norm();
+            let rotation_offset = local_transform
+                .get_rotation()
+                .angle_to(rest_local_transform.get_rotation());
+            let scale_offset =
+                (local_transform.get_scale() - rest_local_transform.get_scale()).norm();
+
+            translation_offset > STATIC_EPSILON
+                || rotation_offset > STATIC_EPSILON
+                || scale_offset > STATIC_EPSILON
+        })
+    }
+
+    /// Move the frames from the `old` skeleton onto its pruned version
+    ///
+    /// The removed joints are not animated so their
+    /// local transforms are collapsed into their kept descendants
+    pub(crate) fn prune(&mut self, old: &Skeleton, new: &Skeleton, new_indices: &[Option<usize>]) {
+        let mut tracks = vec![Track::new(); new.get_bones().len()];
+        for frame_idx in 0..self.times.len() {
+            for (idx, new_idx) in new_indices.iter().enumerate() {
+                if let Some(new_idx) = new_idx {
+                    let mut local_transform: Matrix4<f32> = self.tracks[idx].get(frame_idx).into();
+                    for ancestor in old.ancestors(BoneId(idx)) {
+                        if new_indices[ancestor.0].is_some() {
+                            break;
+                        }
+                        let ancestor_transform: Matrix4<f32> =
+                            self.tracks[ancestor.0].get(frame_idx).into();
+                        local_transform = ancestor_transform * local_transform;
+                    }
+
+                    tracks[*new_idx].push(&(&local_transform).into());
+                }
+            }
+        }
+        self.tracks = tracks;
+
+        if self.is_baked() {
+            self.bake(new);
+        }
+    }
+
+    /// Iterate over the sampled frames of the animation
+    ///
+    /// Each item gives the start time of the frame and the local
+    /// transforms of the joints, indexed by joint index
+    pub fn get_sampled_local_transforms(&self) -> impl Iterator<Item = (f32, Vec<Transform>)> + '_ {
+        self.times
+            .iter()
+            .enumerate()
+            .map(move |(frame_idx, time)| (*time, self.get_frame_local_transforms(frame_idx)))
+    }
 }
 
-fn compute_final_transforms(
-    skeleton: &Skeleton,
-    bone_local_transforms: &HashMap<String, Matrix4<f32>>,
-    global_inverse_transform: &Matrix4<f32>,
-) -> Vec<Matrix4<f32>> {
-    let root = skeleton.get_root().as_ref().unwrap();
-
-    let mut transforms = vec![Matrix4::identity(); skeleton.get_num_vertices_attached_bones()];
-    recursive_final_transforms(
-        skeleton,
-        &root,
-        &Matrix4::identity(),
-        bone_local_transforms,
-        global_inverse_transform,
-        &mut transforms,
-    );
-    transforms
+/// Retrieve the `<marker name="..." time="..."/>` elements of the animation
+/// libraries, e.g. in the `<extra>` technique of an animation clip
+fn markers(element: &xml::Element, in_animations: bool) -> Vec<AnimationEvent> {
+    let mut events = vec![];
+    for child in &element.children {
+        if let xml::Xml::ElementNode(child) = child {
+            if in_animations && child.name == "marker" {
+                let name = child.get_attribute("name", None);
+                let time = child
+                    .get_attribute("time", None)
+                    .and_then(|time| time.trim().parse().ok());
+                if let (Some(name), Some(time)) = (name, time) {
+                    events.push(AnimationEvent {
+                        name: name.to_owned(),
+                        time,
+                    });
+                }
+            } else {
+                let in_animations = in_animations
+                    || child.name == "library_animations"
+                    || child.name == "library_animation_clips";
+                events.extend(markers(child, in_animations));
+            }
+        }
+    }
+
+    events
 }
-fn recursive_final_transforms(
+
+/// The part of a rotation around an axis
+fn twist(rotation: &UnitQuaternion<f32>, axis: &Unit<Vector3<f32>>) -> UnitQuaternion<f32> {
+    let q = rotation.quaternion();
+    let twist = Quaternion::from_parts(q.w, axis.into_inner() * q.imag().dot(axis));
+    if twist.norm() > STATIC_EPSILON {
+        UnitQuaternion::new_normalize(twist)
+    } else {
+        // Half a turn around a perpendicular axis, without any twist
+        UnitQuaternion::identity()
+    }
+}
+
+/// Compute the skinning matrices from the local transforms of the joints
+pub(crate) fn compute_final_transforms(
     skeleton: &Skeleton,
-    bone: &Bone,
-    parent_transform: &Matrix4<f32>,
-    bone_local_transforms: &HashMap<String, Matrix4<f32>>,
+    bone_local_transforms: &[Matrix4<f32>],
     global_inverse_transform: &Matrix4<f32>,
-
-    final_transforms: &mut Vec<Matrix4<f32>>,
+    transforms: &mut Vec<Matrix4<f32>>,
 ) {
-    let name = bone.get_name(skeleton);
+    let global_transforms = compute_global_transforms(skeleton, bone_local_transforms);
 
-    let bone_local_transform = bone_local_transforms.get(name).unwrap();
-    let local_transform = parent_transform * bone_local_transform;
-
-    if bone.has_vertices_attached() {
-        let final_transform =
-            global_inverse_transform * local_transform * bone.get_inverse_bind_pose();
-
-        let idx_transform = bone.idx_transform.unwrap();
-        final_transforms[idx_transform] = final_transform;
+    transforms.clear();
+    transforms.resize(
+        skeleton.get_num_vertices_attached_bones(),
+        Matrix4::identity(),
+    );
+    for (bone, global_transform) in skeleton.get_bones().iter().zip(global_transforms.iter()) {
+        if let Some(idx_transform) = bone.idx_transform {
+            transforms[idx_transform] =
+                global_inverse_transform * global_transform * bone.get_inverse_bind_pose();
+        }
     }
+}
 
-    if let Some(children) = bone.get_children() {
-        for child in children {
-            recursive_final_transforms(
-                skeleton,
-                child,
-                &local_transform,
-                bone_local_transforms,
-                global_inverse_transform,
-                final_transforms,
-            );
-        }
+/// Compute the model space transforms of the joints from their local transforms
+///
+/// The joints are stored parents before their children so that
+/// the global transforms are computed in one pass
+pub(crate) fn compute_global_transforms(
+    skeleton: &Skeleton,
+    bone_local_transforms: &[Matrix4<f32>],
+) -> Vec<Matrix4<f32>> {
+    let mut global_transforms: Vec<Matrix4<f32>> = Vec::with_capacity(bone_local_transforms.len());
+    for (bone, bone_local_transform) in skeleton.get_bones().iter().zip(bone_local_transforms) {
+        let global_transform = if let Some(parent) = bone.get_parent_idx() {
+            global_transforms[parent] * bone_local_transform
+        } else {
+            *bone_local_transform
+        };
+        global_transforms.push(global_transform);
     }
+
+    global_transforms
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -249,7 +882,14 @@ pub struct Animations {
     skeleton: Skeleton,
 }
 
-use super::skeleton::{Bone, Skeleton};
+use super::bvh::Bvh;
+use super::constraint::{apply_constraints, Constraint};
+use super::mask::BoneMask;
+use super::pose::Pose;
+use super::retarget;
+use super::skeleton::{BoneId, Skeleton};
+use super::Error;
+use std::path::Path;
 /*fn extract_anim_name_from_target(target: &str) -> &str {
     target.split('/').collect::<Vec<_>>()[1]
 }*/
@@ -259,8 +899,8 @@ impl Animations {
         name: &str,
         doc: &collada::document::ColladaDocument,
         frame_time: f32,
-    ) -> Option<Self> {
-        if let Some(skeleton) = Skeleton::from(doc) {
+    ) -> Result<Option<Self>, Error> {
+        if let Some(skeleton) = Skeleton::from(doc)? {
             if let Some(animations) = doc.get_animations() {
                 // If the skeleton and animations are defined, therefore there is a bind data associated to it
                 // We can unwrap to get this
@@ -291,37 +931,418 @@ impl Animations {
                     anims.insert(cur_name, res);
                 }
                 */
-                let anim = Animation::new(&skeleton, animations, frame_time);
+                let mut anim = Animation::new(&skeleton, animations, frame_time);
+                for event in markers(&doc.root_element, false) {
+                    anim.add_event(&event.name, event.time)?;
+                }
                 let mut anims = HashMap::new();
                 anims.insert(name.to_string(), anim);
 
-                Some(Animations { anims, skeleton })
+                Ok(Some(Animations { anims, skeleton }))
             } else {
-                None
+                Ok(None)
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
     pub fn append(&mut self, other: Self) {
         assert_eq!(&self.skeleton, &other.skeleton);
-        assert_eq!(other.anims.keys().len(), 1);
 
         for (name, anim) in other.anims {
             self.anims.insert(name, anim);
         }
     }
 
+    /// Import a BVH motion capture file as a new animation
+    ///
+    /// The BVH joints are mapped onto the joints of the skeleton by name.
+    /// `joint_map` renames the BVH joints (BVH name -> skeleton name) for
+    /// rigs whose joint names differ, the other joints keep their names.
+    /// The motion is then sampled at `fps` like the COLLADA animations.
+    pub fn append_bvh<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        path: P,
+        joint_map: &HashMap<String, String>,
+        fps: f32,
+    ) -> Result<(), Error> {
+        let frame_time = 1.0 / fps;
+        if !frame_time.is_finite() || frame_time <= 0.0 {
+            return Err(Error::InvalidFrameTime { frame_time });
+        }
+
+        let bvh = Bvh::from_path(path)?;
+        let bone_animations = bvh.to_bone_animations(&self.skeleton, joint_map)?;
+
+        let anim = Animation::new(&self.skeleton, bone_animations, frame_time);
+        self.anims.insert(name.to_string(), anim);
+
+        Ok(())
+    }
+
+    /// Retarget all the animations onto another skeleton
+    ///
+    /// `joint_map` renames the joints of this skeleton (source name -> target name)
+    /// for rigs whose joint names differ, the other joints are matched by name.
+    /// The local rotations are carried over and the translations are rescaled
+    /// by the ratio of the bone lengths. The returned animations can be appended to
+    /// the animations of the target skeleton. The additive clips are not retargeted.
+    pub fn retarget(
+        &self,
+        skeleton: &Skeleton,
+        joint_map: &HashMap<String, String>,
+    ) -> Result<Animations, Error> {
+        let matches = retarget::match_joints(&self.skeleton, skeleton, joint_map)?;
+
+        let anims = self
+            .anims
+            .iter()
+            .filter(|(_, anim)| !anim.is_additive())
+            .map(|(name, anim)| {
+                let bone_animations =
+                    retarget::retarget_animation(&self.skeleton, anim, skeleton, &matches);
+                let anim = Animation::new(skeleton, bone_animations, anim.get_frame_time());
+
+                (name.clone(), anim)
+            })
+            .collect();
+
+        Ok(Animations {
+            anims,
+            skeleton: skeleton.clone(),
+        })
+    }
+
+    /// Write an animation to a BVH motion capture file
+    ///
+    /// The hierarchy is given by the skeleton and each joint has
+    /// position and rotation channels sampled at the frame time of the animation.
+    pub fn write_bvh<P: AsRef<Path>>(&self, name: &str, path: P) -> Result<(), Error> {
+        let anim = self.get_clip_or_err(name)?;
+
+        Bvh::from_animation(&self.skeleton, anim)?.write(path)
+    }
+
     pub fn get_animation(&self, name: &str) -> Option<&Animation> {
         self.anims.get(name)
     }
 
-    pub fn query(&self, name: &str, time: f32) -> &Vec<Matrix4<f32>> {
-        self.anims[name].query(time)
+    pub fn get_animation_mut(&mut self, name: &str) -> Option<&mut Animation> {
+        self.anims.get_mut(name)
+    }
+
+    /// Iterate over the animations and their names
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Animation)> {
+        self.anims.iter()
+    }
+
+    /// Query the skinning matrices of an animation at a specific time,
+    /// see `Animation::query`
+    pub fn query(
+        &self,
+        name: &str,
+        time: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        let anim = self.get_clip_or_err(name)?;
+        anim.query(&self.skeleton, time, transforms);
+        Ok(())
+    }
+
+    /// Query the skinning matrices of an animation at a normalized time,
+    /// see `Animation::query_normalized`
+    pub fn query_normalized(
+        &self,
+        name: &str,
+        normalized_time: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        let anim = self.get_clip_or_err(name)?;
+        anim.query_normalized(&self.skeleton, normalized_time, transforms);
+        Ok(())
+    }
+
+    /// Bake the skinning matrices of all the animations, see `Animation::bake`
+    pub fn bake(&mut self) {
+        for anim in self.anims.values_mut().filter(|anim| !anim.is_additive()) {
+            anim.bake(&self.skeleton);
+        }
+    }
+
+    fn get_animation_or_err(&self, name: &str) -> Result<&Animation, Error> {
+        self.get_animation(name)
+            .ok_or_else(|| Error::AnimationNotFound {
+                name: name.to_owned(),
+            })
+    }
+
+    /// A clip that can be played on its own, the additive clips
+    /// only make sense on top of another pose
+    pub(crate) fn get_clip_or_err(&self, name: &str) -> Result<&Animation, Error> {
+        let anim = self.get_animation_or_err(name)?;
+        if anim.is_additive() {
+            return Err(Error::AdditiveAnimation {
+                name: name.to_owned(),
+            });
+        }
+
+        Ok(anim)
+    }
+
+    /// Query the model space transforms of all the joints at a specific time,
+    /// indexed by joint index
+    ///
+    /// Contrary to `query`, this also returns the joints without any vertex
+    /// attached to them, e.g. helper or socket bones
+    pub fn query_model_pose(&self, name: &str, time: f32) -> Result<Vec<Matrix4<f32>>, Error> {
+        let anim = self.get_clip_or_err(name)?;
+        Ok(anim.query_model_pose(&self.skeleton, time))
+    }
+
+    /// Query the local transforms of the joints at a specific time
+    pub fn query_pose(&self, name: &str, time: f32) -> Result<Pose, Error> {
+        let anim = self.get_clip_or_err(name)?;
+        Ok(anim.query_pose(time))
+    }
+
+    /// Attach a named event at a time of a clip
+    pub fn add_event(&mut self, name: &str, event: &str, time: f32) -> Result<(), Error> {
+        let anim = self
+            .anims
+            .get_mut(name)
+            .ok_or_else(|| Error::AnimationNotFound {
+                name: name.to_owned(),
+            })?;
+        anim.add_event(event, time)
+    }
+
+    /// Read the events of the clips from a JSON sidecar file
+    ///
+    /// The file maps the name of each clip to its events, e.g.
+    /// `{ "walk": [{ "name": "footstep_left", "time": 0.3 }] }`
+    pub fn read_events<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let file = std::fs::File::open(path)?;
+        let clips: HashMap<String, Vec<AnimationEvent>> =
+            serde_json::from_reader(std::io::BufReader::new(file)).map_err(Error::Json)?;
+        for (name, events) in clips {
+            for event in events {
+                self.add_event(&name, &event.name, event.time)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The events of a clip crossed between two times, see `Animation::events_between`
+    pub fn events_between(
+        &self,
+        name: &str,
+        previous_time: f32,
+        time: f32,
+    ) -> Result<Vec<&AnimationEvent>, Error> {
+        let anim = self.get_animation_or_err(name)?;
+        Ok(anim.events_between(previous_time, time))
+    }
+
+    /// Extract the root motion of every clip, see `Animation::extract_root_motion`
+    ///
+    /// This modifies the loaded clips rather than being an import option, it is
+    /// called once after `load` and before the clips are queried. The additive
+    /// clips hold offsets from a reference pose and are left unchanged.
+    pub fn extract_root_motion(
+        &mut self,
+        bone_name: &str,
+        up: &Unit<Vector3<f32>>,
+    ) -> Result<(), Error> {
+        let id = self
+            .skeleton
+            .find_bone(bone_name)
+            .ok_or_else(|| Error::BoneNotFound {
+                name: bone_name.to_owned(),
+            })?;
+        // Check all the clips before modifying any of them
+        if self
+            .anims
+            .values()
+            .any(|anim| anim.get_root_motion().is_some())
+        {
+            return Err(Error::RootMotionAlreadyExtracted);
+        }
+        for anim in self.anims.values_mut().filter(|anim| !anim.is_additive()) {
+            anim.extract_root_motion(&self.skeleton, id, up)?;
+        }
+
+        Ok(())
+    }
+
+    /// The motion of the character between two times of a clip,
+    /// see `Animation::query_root_motion`
+    pub fn query_root_motion(
+        &self,
+        name: &str,
+        previous_time: f32,
+        time: f32,
+    ) -> Result<Isometry3<f32>, Error> {
+        let anim = self.get_animation_or_err(name)?;
+        Ok(anim.query_root_motion(previous_time, time))
+    }
+
+    /// Convert a clip into an additive clip, see `Animation::make_additive`
+    pub fn make_additive(&mut self, name: &str, reference: AdditiveReference) -> Result<(), Error> {
+        let anim = self
+            .anims
+            .get_mut(name)
+            .ok_or_else(|| Error::AnimationNotFound {
+                name: name.to_owned(),
+            })?;
+        anim.make_additive(&self.skeleton, reference);
+
+        Ok(())
+    }
+
+    /// Add an additive clip sampled at a specific time on top of a pose
+    ///
+    /// A weight of 0.0 leaves the pose unchanged, 1.0 adds the full offsets
+    pub fn add(&self, pose: &mut Pose, name: &str, time: f32, weight: f32) -> Result<(), Error> {
+        let anim = self.get_animation_or_err(name)?;
+        if !anim.is_additive() {
+            return Err(Error::NotAdditiveAnimation {
+                name: name.to_owned(),
+            });
+        }
+        pose.add(&anim.query_pose(time), weight);
+
+        Ok(())
+    }
+
+    /// Layer a clip sampled at a specific time on the bones of a mask
+    ///
+    /// The pose is blended towards the clip, or the offsets of the clip are
+    /// added to it if the clip is additive. The weight of each bone is
+    /// scaled by the one of the mask.
+    pub fn layer(
+        &self,
+        pose: &mut Pose,
+        name: &str,
+        time: f32,
+        weight: f32,
+        mask: &BoneMask,
+    ) -> Result<(), Error> {
+        let anim = self.get_animation_or_err(name)?;
+        let layer = anim.query_pose(time);
+        if anim.is_additive() {
+            pose.add_masked(&layer, weight, mask)?;
+        } else {
+            *pose = pose.blend_masked(&layer, weight, mask)?;
+        }
+
+        Ok(())
+    }
+
+    /// Blend the poses of two clips sampled at their own time
+    ///
+    /// A weight of 0.0 gives the pose of `clip_a`, 1.0 the pose of `clip_b`
+    pub fn blend_pose(
+        &self,
+        clip_a: &str,
+        time_a: f32,
+        clip_b: &str,
+        time_b: f32,
+        weight: f32,
+    ) -> Result<Pose, Error> {
+        let pose_a = self.query_pose(clip_a, time_a)?;
+        let pose_b = self.query_pose(clip_b, time_b)?;
+
+        Ok(pose_a.blend(&pose_b, weight))
+    }
+
+    /// Query the skinning matrices of two blended clips, see `blend_pose`
+    ///
+    /// The local transforms of the bones are blended before the
+    /// skinning matrices are written into `transforms`
+    pub fn blend(
+        &self,
+        clip_a: &str,
+        time_a: f32,
+        clip_b: &str,
+        time_b: f32,
+        weight: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        let pose = self.blend_pose(clip_a, time_a, clip_b, time_b, weight)?;
+        pose.write_skinning_transforms(&self.skeleton, transforms);
+
+        Ok(())
+    }
+
+    /// Query the skinning matrices at a specific time with constraints applied
+    ///
+    /// The constraints are applied in order on the sampled pose,
+    /// before the skinning matrices are computed
+    pub fn query_constrained(
+        &self,
+        name: &str,
+        time: f32,
+        constraints: &[Constraint],
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        let mut pose = self.query_pose(name, time)?;
+        apply_constraints(&self.skeleton, &mut pose, constraints);
+        pose.write_skinning_transforms(&self.skeleton, transforms);
+
+        Ok(())
+    }
+
+    /// Query the model space transform of a bone at a specific time
+    ///
+    /// This is the transform to apply to an object attached
+    /// to the bone, e.g. a sword in the hand bone
+    pub fn query_bone_transform(
+        &self,
+        name: &str,
+        time: f32,
+        bone_name: &str,
+    ) -> Result<Matrix4<f32>, Error> {
+        let anim = self.get_clip_or_err(name)?;
+        let id = self
+            .skeleton
+            .find_bone(bone_name)
+            .ok_or_else(|| Error::BoneNotFound {
+                name: bone_name.to_owned(),
+            })?;
+
+        Ok(anim.query_bone_transform(&self.skeleton, time, id))
     }
 
     pub fn get_skeleton(&self) -> &Skeleton {
         &self.skeleton
     }
+
+    /// Whether a joint is animated by one of the animations
+    pub(crate) fn is_joint_animated(&self, id: BoneId) -> bool {
+        let rest_local_transform: Transform =
+            self.skeleton.get_bone(id).get_rest_local_transform().into();
+        self.anims
+            .values()
+            .any(|anim| anim.is_joint_animated(id.0, &rest_local_transform))
+    }
+
+    /// Replace the skeleton by the one made of the kept bones
+    ///
+    /// Returns the new index of each old bone
+    pub(crate) fn prune(
+        &mut self,
+        keep: &[bool],
+        skinning_indices: &[Option<usize>],
+    ) -> Vec<Option<usize>> {
+        let (skeleton, new_indices) = self.skeleton.prune(keep, skinning_indices);
+        for anim in self.anims.values_mut() {
+            anim.prune(&self.skeleton, &skeleton, &new_indices);
+        }
+        self.skeleton = skeleton;
+
+        new_indices
+    }
 }
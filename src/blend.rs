@@ -0,0 +1,429 @@
+use super::animation::Animations;
+use super::pose::Pose;
+use super::Error;
+
+use na::{Matrix4, Point2, Vector2};
+use serde::{Deserialize, Serialize};
+
+/// Area under which a triangle is considered degenerate
+const EPSILON: f32 = 1e-6;
+
+/// A transition from one clip to another whose blend weight
+/// ramps linearly from 0.0 to 1.0 over a duration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crossfade {
+    from: String,
+    to: String,
+    duration: f32,
+    elapsed: f32,
+}
+
+impl Crossfade {
+    pub fn new(from: &str, to: &str, duration: f32) -> Self {
+        Crossfade {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            duration,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Move the crossfade forward in time
+    pub fn advance(&mut self, dt: f32) {
+        self.elapsed = (self.elapsed + dt).min(self.duration.max(0.0));
+    }
+
+    /// The weight of the target clip
+    pub fn get_weight(&self) -> f32 {
+        if self.duration > 0.0 {
+            (self.elapsed / self.duration).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    }
+
+    /// Whether only the target clip is played
+    pub fn is_finished(&self) -> bool {
+        self.get_weight() >= 1.0
+    }
+
+    pub fn get_from(&self) -> &str {
+        &self.from
+    }
+
+    pub fn get_to(&self) -> &str {
+        &self.to
+    }
+
+    /// Query the skinning matrices of the two clips blended with the current weight
+    ///
+    /// Each clip is sampled at its own time, e.g. so that the target clip starts
+    /// from its beginning when the crossfade starts
+    pub fn query(
+        &self,
+        animations: &Animations,
+        time_from: f32,
+        time_to: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        animations.blend(
+            &self.from,
+            time_from,
+            &self.to,
+            time_to,
+            self.get_weight(),
+            transforms,
+        )
+    }
+}
+
+/// Clips placed along one parameter, e.g. idle, walk and run by speed
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct BlendSpace1D {
+    // Sorted by parameter
+    clips: Vec<(String, f32)>,
+}
+
+impl BlendSpace1D {
+    pub fn new() -> Self {
+        BlendSpace1D { clips: vec![] }
+    }
+
+    /// Place a clip at a parameter value
+    pub fn add_clip(&mut self, name: &str, parameter: f32) {
+        let idx = self.clips.partition_point(|(_, p)| *p <= parameter);
+        self.clips.insert(idx, (name.to_owned(), parameter));
+    }
+
+    pub fn get_clips(&self) -> &[(String, f32)] {
+        &self.clips
+    }
+
+    /// The weights of the clips at a parameter value, the value
+    /// being clamped to the range of the clips
+    pub fn get_weights(&self, parameter: f32) -> Vec<(&str, f32)> {
+        let idx = self.clips.partition_point(|(_, p)| *p <= parameter);
+        if idx == 0 || idx == self.clips.len() {
+            // Before the first clip or after the last one
+            return self
+                .clips
+                .get(idx.saturating_sub(1))
+                .map(|(name, _)| vec![(name.as_str(), 1.0)])
+                .unwrap_or_default();
+        }
+
+        let (name0, p0) = &self.clips[idx - 1];
+        let (name1, p1) = &self.clips[idx];
+        let alpha = (parameter - p0) / (p1 - p0);
+        vec![(name0.as_str(), 1.0 - alpha), (name1.as_str(), alpha)]
+    }
+
+    /// The duration of a cycle at a parameter value, see `blend_duration`
+    pub fn get_duration(&self, animations: &Animations, parameter: f32) -> Result<f32, Error> {
+        blend_duration(animations, &self.get_weights(parameter))
+    }
+
+    /// Query the blended pose at a parameter value, see `blend_poses`
+    pub fn query_pose(
+        &self,
+        animations: &Animations,
+        parameter: f32,
+        normalized_time: f32,
+    ) -> Result<Pose, Error> {
+        blend_poses(animations, &self.get_weights(parameter), normalized_time)
+    }
+
+    /// Query the skinning matrices of the blended pose at a parameter value
+    pub fn query(
+        &self,
+        animations: &Animations,
+        parameter: f32,
+        normalized_time: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        let pose = self.query_pose(animations, parameter, normalized_time)?;
+        pose.write_skinning_transforms(animations.get_skeleton(), transforms);
+
+        Ok(())
+    }
+}
+
+/// Clips placed on a plane, e.g. walk and strafe clips by velocity
+///
+/// The clips are triangulated and the weights at a parameter are the
+/// barycentric coordinates in the triangle containing it
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct BlendSpace2D {
+    clips: Vec<(String, Point2<f32>)>,
+    // Indices of the clips of each triangle
+    triangles: Vec<[usize; 3]>,
+}
+
+impl BlendSpace2D {
+    pub fn new() -> Self {
+        BlendSpace2D {
+            clips: vec![],
+            triangles: vec![],
+        }
+    }
+
+    /// Place a clip at a parameter position
+    pub fn add_clip(&mut self, name: &str, parameter: Point2<f32>) {
+        self.clips.push((name.to_owned(), parameter));
+        self.triangulate();
+    }
+
+    pub fn get_clips(&self) -> &[(String, Point2<f32>)] {
+        &self.clips
+    }
+
+    pub fn get_triangles(&self) -> &[[usize; 3]] {
+        &self.triangles
+    }
+
+    /// The weights of the clips at a parameter position
+    ///
+    /// A position outside of the triangles is moved
+    /// onto the closest point of the triangulation.
+    /// There are no weights at a non-finite position.
+    pub fn get_weights(&self, parameter: &Point2<f32>) -> Vec<(&str, f32)> {
+        if !parameter.x.is_finite() || !parameter.y.is_finite() {
+            return vec![];
+        }
+
+        let mut closest: Option<(f32, [usize; 3], Vector2<f32>)> = None;
+        for triangle in &self.triangles {
+            let (a, b, c) = (
+                self.clips[triangle[0]].1,
+                self.clips[triangle[1]].1,
+                self.clips[triangle[2]].1,
+            );
+            let point = closest_point_on_triangle(parameter, &a, &b, &c);
+            let distance = (point - parameter).norm_squared();
+            if closest.map(|(d, _, _)| distance < d) != Some(false) {
+                let (u, v) = barycentric(&point, &a, &b, &c);
+                closest = Some((distance, *triangle, Vector2::new(u, v)));
+            }
+        }
+
+        if let Some((_, [a, b, c], uv)) = closest {
+            vec![
+                (self.clips[a].0.as_str(), 1.0 - uv.x - uv.y),
+                (self.clips[b].0.as_str(), uv.x),
+                (self.clips[c].0.as_str(), uv.y),
+            ]
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect()
+        } else {
+            // Less than three clips, or all on a line: take the closest one
+            self.clips
+                .iter()
+                .min_by(|(_, p0), (_, p1)| {
+                    let d0 = (p0 - parameter).norm_squared();
+                    let d1 = (p1 - parameter).norm_squared();
+                    d0.total_cmp(&d1)
+                })
+                .map(|(name, _)| vec![(name.as_str(), 1.0)])
+                .unwrap_or_default()
+        }
+    }
+
+    /// The duration of a cycle at a parameter position, see `blend_duration`
+    pub fn get_duration(
+        &self,
+        animations: &Animations,
+        parameter: &Point2<f32>,
+    ) -> Result<f32, Error> {
+        blend_duration(animations, &self.get_weights(parameter))
+    }
+
+    /// Query the blended pose at a parameter position, see `blend_poses`
+    pub fn query_pose(
+        &self,
+        animations: &Animations,
+        parameter: &Point2<f32>,
+        normalized_time: f32,
+    ) -> Result<Pose, Error> {
+        blend_poses(animations, &self.get_weights(parameter), normalized_time)
+    }
+
+    /// Query the skinning matrices of the blended pose at a parameter position
+    pub fn query(
+        &self,
+        animations: &Animations,
+        parameter: &Point2<f32>,
+        normalized_time: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        let pose = self.query_pose(animations, parameter, normalized_time)?;
+        pose.write_skinning_transforms(animations.get_skeleton(), transforms);
+
+        Ok(())
+    }
+
+    /// Delaunay triangulation of the clip positions
+    ///
+    /// A triangle is kept when no other clip is strictly inside its
+    /// circumcircle. The few clips of a blend space make the brute force
+    /// fast enough. With four clips on a circle, e.g. on a grid, both
+    /// triangulations of the quad are Delaunay: the triangles are visited
+    /// in the lexicographic order of their clip indices and the ones
+    /// overlapping an already kept triangle are dropped.
+    fn triangulate(&mut self) {
+        let points = self.clips.iter().map(|(_, p)| *p).collect::<Vec<_>>();
+        let num_points = points.len();
+
+        self.triangles.clear();
+        for i in 0..num_points {
+            for j in i + 1..num_points {
+                for k in j + 1..num_points {
+                    let (a, b, c) = (&points[i], &points[j], &points[k]);
+                    if (b - a).perp(&(c - a)).abs() <= EPSILON {
+                        continue;
+                    }
+
+                    let is_delaunay = points
+                        .iter()
+                        .enumerate()
+                        .filter(|(idx, _)| *idx != i && *idx != j && *idx != k)
+                        .all(|(_, p)| !in_circumcircle(p, a, b, c));
+                    let overlaps = self.triangles.iter().any(|triangle| {
+                        let [d, e, f] = triangle.map(|idx| &points[idx]);
+                        triangles_overlap([a, b, c], [d, e, f])
+                    });
+                    if is_delaunay && !overlaps {
+                        self.triangles.push([i, j, k]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Blend the poses of weighted clips, each sampled at the same normalized time
+///
+/// Sampling by normalized time keeps the clips in phase, e.g. the feet of a
+/// walk and a run touch the ground at the same time
+fn blend_poses(
+    animations: &Animations,
+    weights: &[(&str, f32)],
+    normalized_time: f32,
+) -> Result<Pose, Error> {
+    let mut blended: Option<Pose> = None;
+    let mut total_weight = 0.0;
+    for (name, weight) in weights {
+        let anim = animations.get_clip_or_err(name)?;
+        let pose = anim.query_pose(normalized_time * anim.get_duration());
+
+        // Blending each pose with its share of the accumulated weight
+        // gives the weighted average of the poses
+        total_weight += weight;
+        blended = Some(match blended {
+            Some(blended) if total_weight > 0.0 => blended.blend(&pose, weight / total_weight),
+            Some(blended) => blended,
+            None => pose,
+        });
+    }
+
+    blended.ok_or(Error::EmptyBlendSpace)
+}
+
+/// The weighted average of the durations of the clips
+///
+/// Advancing the normalized time by `dt / duration` plays
+/// the blended clips at a consistent speed
+fn blend_duration(animations: &Animations, weights: &[(&str, f32)]) -> Result<f32, Error> {
+    if weights.is_empty() {
+        return Err(Error::EmptyBlendSpace);
+    }
+
+    weights.iter().try_fold(0.0, |duration, (name, weight)| {
+        let anim = animations.get_clip_or_err(name)?;
+        Ok(duration + weight * anim.get_duration())
+    })
+}
+
+/// Whether a point is strictly inside the circumcircle of a triangle
+fn in_circumcircle(p: &Point2<f32>, a: &Point2<f32>, b: &Point2<f32>, c: &Point2<f32>) -> bool {
+    let (a, b, c) = (a - p, b - p, c - p);
+    let det = a.norm_squared() * b.perp(&c) - b.norm_squared() * a.perp(&c)
+        + c.norm_squared() * a.perp(&b);
+
+    // The sign depends on the orientation of the triangle
+    let orientation = (b - a).perp(&(c - a));
+    det * orientation > EPSILON
+}
+
+/// Whether the interiors of two triangles intersect
+///
+/// The triangles sharing an edge or a vertex do not overlap. Two convex
+/// shapes are disjoint when their projections on the normal of one of
+/// their edges are separated.
+fn triangles_overlap(t0: [&Point2<f32>; 3], t1: [&Point2<f32>; 3]) -> bool {
+    let project = |t: &[&Point2<f32>; 3], axis: &Vector2<f32>| {
+        t.iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), p| {
+                let d = p.coords.dot(axis);
+                (min.min(d), max.max(d))
+            })
+    };
+
+    [t0, t1].iter().all(|t| {
+        (0..3).all(|idx| {
+            let edge = t[(idx + 1) % 3] - t[idx];
+            let axis = Vector2::new(-edge.y, edge.x).normalize();
+            let (min0, max0) = project(&t0, &axis);
+            let (min1, max1) = project(&t1, &axis);
+
+            // The projections of a shared edge only differ by rounding errors
+            let tolerance = EPSILON
+                * [min0, max0, min1, max1]
+                    .iter()
+                    .fold(1.0, |scale: f32, d| scale.max(d.abs()));
+            max0 - min1 > tolerance && max1 - min0 > tolerance
+        })
+    })
+}
+
+/// The barycentric coordinates of `p` relative to `b` and `c`
+fn barycentric(p: &Point2<f32>, a: &Point2<f32>, b: &Point2<f32>, c: &Point2<f32>) -> (f32, f32) {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let area = ab.perp(&ac);
+    let u = ap.perp(&ac) / area;
+    let v = ab.perp(&ap) / area;
+
+    (u, v)
+}
+
+/// The point of a triangle closest to `p`
+fn closest_point_on_triangle(
+    p: &Point2<f32>,
+    a: &Point2<f32>,
+    b: &Point2<f32>,
+    c: &Point2<f32>,
+) -> Point2<f32> {
+    let (u, v) = barycentric(p, a, b, c);
+    if u >= 0.0 && v >= 0.0 && u + v <= 1.0 {
+        return *p;
+    }
+
+    let closest_on_segment = |s0: &Point2<f32>, s1: &Point2<f32>| {
+        let d = s1 - s0;
+        let alpha = ((p - s0).dot(&d) / d.norm_squared()).clamp(0.0, 1.0);
+        s0 + d * alpha
+    };
+    [
+        closest_on_segment(a, b),
+        closest_on_segment(b, c),
+        closest_on_segment(c, a),
+    ]
+    .iter()
+    .min_by(|q0, q1| {
+        let d0 = (*q0 - p).norm_squared();
+        let d1 = (*q1 - p).norm_squared();
+        d0.total_cmp(&d1)
+    })
+    .copied()
+    .unwrap()
+}
@@ -0,0 +1,634 @@
+use super::animation::Animation;
+use super::skeleton::Skeleton;
+use super::transform::{from_matrix4, Transform};
+use super::Error;
+
+use na::{Isometry3, Matrix4, Translation3, UnitQuaternion, Vector3};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Channel {
+    Xposition,
+    Yposition,
+    Zposition,
+    Xrotation,
+    Yrotation,
+    Zrotation,
+}
+
+impl Channel {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "Xposition" => Some(Channel::Xposition),
+            "Yposition" => Some(Channel::Yposition),
+            "Zposition" => Some(Channel::Zposition),
+            "Xrotation" => Some(Channel::Xrotation),
+            "Yrotation" => Some(Channel::Yrotation),
+            "Zrotation" => Some(Channel::Zrotation),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Channel::Xposition => "Xposition",
+            Channel::Yposition => "Yposition",
+            Channel::Zposition => "Zposition",
+            Channel::Xrotation => "Xrotation",
+            Channel::Yrotation => "Yrotation",
+            Channel::Zrotation => "Zrotation",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BvhJoint {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub offset: Vector3<f32>,
+    pub channels: Vec<Channel>,
+}
+
+impl BvhJoint {
+    fn has_position_channels(&self) -> bool {
+        self.channels.iter().any(|c| {
+            matches!(
+                c,
+                Channel::Xposition | Channel::Yposition | Channel::Zposition
+            )
+        })
+    }
+
+    /// Local transform of the joint from its channel values
+    ///
+    /// Rotations are applied in the order of the channels,
+    /// positions are added to the offset of the joint
+    fn local_transform(&self, values: &[f32]) -> Isometry3<f32> {
+        let mut t = self.offset;
+        let mut r = UnitQuaternion::identity();
+        for (channel, &v) in self.channels.iter().zip(values.iter()) {
+            match channel {
+                Channel::Xposition => t.x += v,
+                Channel::Yposition => t.y += v,
+                Channel::Zposition => t.z += v,
+                Channel::Xrotation => {
+                    r *= UnitQuaternion::from_axis_angle(&Vector3::x_axis(), v.to_radians())
+                }
+                Channel::Yrotation => {
+                    r *= UnitQuaternion::from_axis_angle(&Vector3::y_axis(), v.to_radians())
+                }
+                Channel::Zrotation => {
+                    r *= UnitQuaternion::from_axis_angle(&Vector3::z_axis(), v.to_radians())
+                }
+            }
+        }
+
+        Isometry3::from_parts(Translation3::from(t), r)
+    }
+}
+
+/// A parsed BVH motion capture file
+#[derive(Debug)]
+pub(crate) struct Bvh {
+    // Joints are stored parents before their children
+    pub joints: Vec<BvhJoint>,
+    pub frame_time: f32,
+    // The channel values of each frame, in the order of the joints
+    pub frames: Vec<Vec<f32>>,
+}
+
+struct Tokens<'a> {
+    tokens: Vec<(usize, &'a str)>,
+    cur: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(content: &'a str) -> Self {
+        let tokens = content
+            .lines()
+            .enumerate()
+            .flat_map(|(line, l)| l.split_whitespace().map(move |t| (line + 1, t)))
+            .collect();
+
+        Tokens { tokens, cur: 0 }
+    }
+
+    fn error(&self, message: &str) -> Error {
+        let line = self
+            .tokens
+            .get(self.cur.min(self.tokens.len().saturating_sub(1)))
+            .map(|(line, _)| *line)
+            .unwrap_or(0);
+
+        Error::Bvh {
+            line,
+            message: message.to_owned(),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.cur).map(|(_, t)| *t)
+    }
+
+    fn next(&mut self) -> Result<&'a str, Error> {
+        let token = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        self.cur += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Error> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            self.cur -= 1;
+            Err(self.error(&format!("expected '{}', found '{}'", expected, token)))
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, Error> {
+        let token = self.next()?;
+        token.parse::<T>().map_err(|_| {
+            self.cur -= 1;
+            self.error(&format!("expected a number, found '{}'", token))
+        })
+    }
+
+    fn vector(&mut self) -> Result<Vector3<f32>, Error> {
+        Ok(Vector3::new(self.number()?, self.number()?, self.number()?))
+    }
+}
+
+impl Bvh {
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(&path).map_err(|_| Error::OpenFile {
+            path: path.as_ref().to_string_lossy().into_owned(),
+        })?;
+
+        Bvh::parse(&content)
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self, Error> {
+        let mut tokens = Tokens::new(content);
+        let mut joints = vec![];
+
+        tokens.expect("HIERARCHY")?;
+        while tokens.peek() == Some("ROOT") {
+            tokens.next()?;
+            Bvh::parse_joint(&mut tokens, None, &mut joints)?;
+        }
+        if joints.is_empty() {
+            return Err(tokens.error("expected 'ROOT'"));
+        }
+
+        tokens.expect("MOTION")?;
+        tokens.expect("Frames:")?;
+        let num_frames: usize = tokens.number()?;
+        tokens.expect("Frame")?;
+        tokens.expect("Time:")?;
+        let frame_time: f32 = tokens.number()?;
+
+        let num_channels = joints
+            .iter()
+            .map(|j: &BvhJoint| j.channels.len())
+            .sum::<usize>();
+        let mut frames = Vec::with_capacity(num_frames);
+        for _ in 0..num_frames {
+            let frame = (0..num_channels)
+                .map(|_| tokens.number())
+                .collect::<Result<Vec<f32>, _>>()?;
+            frames.push(frame);
+        }
+
+        Ok(Bvh {
+            joints,
+            frame_time,
+            frames,
+        })
+    }
+
+    fn parse_joint(
+        tokens: &mut Tokens,
+        parent: Option<usize>,
+        joints: &mut Vec<BvhJoint>,
+    ) -> Result<(), Error> {
+        let name = tokens.next()?.to_owned();
+        tokens.expect("{")?;
+        tokens.expect("OFFSET")?;
+        let offset = tokens.vector()?;
+
+        tokens.expect("CHANNELS")?;
+        let num_channels: usize = tokens.number()?;
+        let channels = (0..num_channels)
+            .map(|_| {
+                let token = tokens.next()?;
+                Channel::parse(token).ok_or_else(|| {
+                    tokens.cur -= 1;
+                    tokens.error(&format!("unknown channel '{}'", token))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let idx = joints.len();
+        joints.push(BvhJoint {
+            name,
+            parent,
+            offset,
+            channels,
+        });
+
+        loop {
+            match tokens.next()? {
+                "JOINT" => Bvh::parse_joint(tokens, Some(idx), joints)?,
+                "End" => {
+                    // End sites only give the length of the last bone of a chain
+                    tokens.expect("Site")?;
+                    tokens.expect("{")?;
+                    tokens.expect("OFFSET")?;
+                    tokens.vector()?;
+                    tokens.expect("}")?;
+                }
+                "}" => return Ok(()),
+                token => {
+                    tokens.cur -= 1;
+                    return Err(tokens.error(&format!("unexpected '{}'", token)));
+                }
+            }
+        }
+    }
+
+    /// Compute the global transforms of the joints for one frame
+    fn global_transforms(&self, frame: &[f32]) -> Vec<Isometry3<f32>> {
+        let mut values = frame;
+        let locals = self
+            .joints
+            .iter()
+            .map(|joint| {
+                let (cur, next) = values.split_at(joint.channels.len());
+                values = next;
+
+                joint.local_transform(cur)
+            })
+            .collect::<Vec<_>>();
+        let parents = self.joints.iter().map(|j| j.parent).collect::<Vec<_>>();
+
+        global_transforms(&parents, &locals)
+    }
+
+    /// Convert a sampled animation of the skeleton into a BVH motion
+    ///
+    /// This is the inverse of `to_bone_animations`: the BVH rest pose is the
+    /// rest pose of the skeleton with its rotations removed, so that the
+    /// offsets are expressed in the world axes. Each joint has position and
+    /// ZXY rotation channels reproducing the global transforms of the skeleton.
+    ///
+    /// BVH names cannot contain whitespace, so such joint names are rejected
+    /// rather than renamed, which would break finding them after a reimport.
+    pub(crate) fn from_animation(skeleton: &Skeleton, anim: &Animation) -> Result<Self, Error> {
+        if let Some(name) = skeleton
+            .get_joint_names()
+            .iter()
+            .find(|name| name.is_empty() || name.contains(char::is_whitespace))
+        {
+            return Err(Error::Bvh {
+                line: 0,
+                message: format!("the joint name '{}' cannot be written in a BVH file", name),
+            });
+        }
+
+        let parents = skeleton.get_parent_indices();
+        let (rest_locals, rest_globals) = rest_transforms(skeleton, &parents);
+
+        let offsets = parents
+            .iter()
+            .zip(rest_locals.iter())
+            .map(|(parent, rest_local)| {
+                let rest_rotation = parent
+                    .map(|p| rest_globals[p].rotation)
+                    .unwrap_or_else(UnitQuaternion::identity);
+                rest_rotation * rest_local.translation.vector
+            })
+            .collect::<Vec<_>>();
+
+        let joints = skeleton
+            .get_joint_names()
+            .iter()
+            .zip(parents.iter().zip(offsets.iter()))
+            .map(|(name, (parent, offset))| BvhJoint {
+                name: name.clone(),
+                parent: *parent,
+                offset: *offset,
+                channels: vec![
+                    Channel::Xposition,
+                    Channel::Yposition,
+                    Channel::Zposition,
+                    Channel::Zrotation,
+                    Channel::Xrotation,
+                    Channel::Yrotation,
+                ],
+            })
+            .collect::<Vec<_>>();
+
+        let frames = anim
+            .get_sampled_local_transforms()
+            .map(|(_, locals)| {
+                let locals = locals
+                    .iter()
+                    .map(isometry_from_transform)
+                    .collect::<Vec<_>>();
+                let globals = global_transforms(&parents, &locals);
+
+                // Rotations of the joints from the BVH rest pose
+                let bvh_rotations = globals
+                    .iter()
+                    .zip(rest_globals.iter())
+                    .map(|(global, rest_global)| global.rotation * rest_global.rotation.inverse())
+                    .collect::<Vec<_>>();
+
+                let mut frame = Vec::with_capacity(6 * joints.len());
+                for (idx, parent) in parents.iter().enumerate() {
+                    let (rotation, position) = if let Some(parent) = parent {
+                        let parent_rotation = bvh_rotations[*parent].inverse();
+                        (
+                            parent_rotation * bvh_rotations[idx],
+                            parent_rotation
+                                * (globals[idx].translation.vector
+                                    - globals[*parent].translation.vector),
+                        )
+                    } else {
+                        (bvh_rotations[idx], globals[idx].translation.vector)
+                    };
+                    let position = position - offsets[idx];
+                    let (z, x, y) = euler_zxy(&rotation);
+
+                    frame.extend_from_slice(&[position.x, position.y, position.z, z, x, y]);
+                }
+
+                frame
+            })
+            .collect();
+
+        Ok(Bvh {
+            joints,
+            frame_time: anim.get_frame_time(),
+            frames,
+        })
+    }
+
+    pub(crate) fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        writeln!(file, "HIERARCHY")?;
+        for (idx, joint) in self.joints.iter().enumerate() {
+            if joint.parent.is_none() {
+                self.write_joint(&mut file, idx, 0)?;
+            }
+        }
+
+        // The channel values of each joint in the frames
+        let mut ranges = vec![];
+        let mut start = 0;
+        for joint in &self.joints {
+            ranges.push(start..start + joint.channels.len());
+            start += joint.channels.len();
+        }
+        // The values are written in the order the joints appear in the hierarchy
+        let order = self.hierarchy_order();
+
+        writeln!(file, "MOTION")?;
+        writeln!(file, "Frames: {}", self.frames.len())?;
+        writeln!(file, "Frame Time: {:.6}", self.frame_time)?;
+        for frame in &self.frames {
+            let values = order
+                .iter()
+                .flat_map(|idx| frame[ranges[*idx].clone()].iter())
+                .map(|v| format!("{:.6}", v))
+                .collect::<Vec<_>>();
+            writeln!(file, "{}", values.join(" "))?;
+        }
+        file.flush()?;
+
+        Ok(())
+    }
+
+    fn children(&self, idx: usize) -> Vec<usize> {
+        self.joints
+            .iter()
+            .enumerate()
+            .filter(|(_, j)| j.parent == Some(idx))
+            .map(|(child, _)| child)
+            .collect()
+    }
+
+    /// The joints in the order `write_joint` writes them,
+    /// each root followed by its descendants depth-first
+    pub(crate) fn hierarchy_order(&self) -> Vec<usize> {
+        let mut order = vec![];
+        let mut stack = self
+            .joints
+            .iter()
+            .enumerate()
+            .filter(|(_, j)| j.parent.is_none())
+            .map(|(idx, _)| idx)
+            .rev()
+            .collect::<Vec<_>>();
+        while let Some(idx) = stack.pop() {
+            order.push(idx);
+            stack.extend(self.children(idx).into_iter().rev());
+        }
+
+        order
+    }
+
+    fn write_joint<W: Write>(&self, file: &mut W, idx: usize, depth: usize) -> Result<(), Error> {
+        let indent = "\t".repeat(depth);
+        let joint = &self.joints[idx];
+
+        let keyword = if joint.parent.is_some() {
+            "JOINT"
+        } else {
+            "ROOT"
+        };
+        writeln!(file, "{}{} {}", indent, keyword, joint.name)?;
+        writeln!(file, "{}{{", indent)?;
+        writeln!(
+            file,
+            "{}\tOFFSET {:.6} {:.6} {:.6}",
+            indent, joint.offset.x, joint.offset.y, joint.offset.z
+        )?;
+        let channels = joint.channels.iter().map(|c| c.name()).collect::<Vec<_>>();
+        writeln!(
+            file,
+            "{}\tCHANNELS {} {}",
+            indent,
+            channels.len(),
+            channels.join(" ")
+        )?;
+
+        let children = self.children(idx);
+        if children.is_empty() {
+            // BVH leaves are ended by an End Site
+            writeln!(file, "{}\tEnd Site", indent)?;
+            writeln!(file, "{}\t{{", indent)?;
+            writeln!(file, "{}\t\tOFFSET 0.000000 0.000000 0.000000", indent)?;
+            writeln!(file, "{}\t}}", indent)?;
+        } else {
+            for child in children {
+                self.write_joint(file, child, depth + 1)?;
+            }
+        }
+        writeln!(file, "{}}}", indent)?;
+
+        Ok(())
+    }
+
+    /// Convert the motion into per-joint animations of the skeleton
+    ///
+    /// The BVH joints are matched with the skeleton joints by name
+    /// after being renamed through `joint_map` (BVH name -> skeleton name).
+    /// BVH rest poses have no rotation, so the global rotation of a BVH
+    /// joint is the rotation of the joint from the rest pose. It is
+    /// applied on top of the global rest orientation of the matched
+    /// skeleton joint. Joints not driven by the BVH keep their rest
+    /// transform relative to their parent, translations of the
+    /// skeleton are kept except for joints having position channels.
+    pub(crate) fn to_bone_animations(
+        &self,
+        skeleton: &Skeleton,
+        joint_map: &HashMap<String, String>,
+    ) -> Result<Vec<collada::Animation>, Error> {
+        if self.frames.len() < 2 {
+            return Err(Error::Bvh {
+                line: 0,
+                message: "at least two frames are required".to_owned(),
+            });
+        }
+
+        let joint_names = skeleton.get_joint_names();
+        // Check that the mapping targets existing joints
+        for name in joint_map.values() {
+            if !joint_names.contains(name) {
+                return Err(Error::BoneNotFound { name: name.clone() });
+            }
+        }
+
+        // Skeleton joint index -> BVH joint index
+        let mut matches = vec![None; joint_names.len()];
+        for (bvh_idx, joint) in self.joints.iter().enumerate() {
+            let name = joint_map.get(&joint.name).unwrap_or(&joint.name);
+            if let Some(idx) = joint_names.iter().position(|n| n == name) {
+                matches[idx] = Some(bvh_idx);
+            }
+        }
+
+        let parents = skeleton.get_parent_indices();
+        let (rest_locals, rest_globals) = rest_transforms(skeleton, &parents);
+
+        let sample_times = (0..self.frames.len())
+            .map(|i| (i as f32) * self.frame_time)
+            .collect::<Vec<_>>();
+        let mut sample_poses = vec![Vec::with_capacity(self.frames.len()); joint_names.len()];
+        for frame in &self.frames {
+            let bvh_globals = self.global_transforms(frame);
+
+            let mut globals: Vec<Isometry3<f32>> = Vec::with_capacity(joint_names.len());
+            for (idx, parent) in parents.iter().enumerate() {
+                let parent_global = parent
+                    .map(|p| globals[p])
+                    .unwrap_or_else(Isometry3::identity);
+                let rest = parent_global * rest_locals[idx];
+
+                let global = if let Some(bvh_idx) = matches[idx] {
+                    let bvh_global = &bvh_globals[bvh_idx];
+                    let rotation = bvh_global.rotation * rest_globals[idx].rotation;
+                    let translation = if self.joints[bvh_idx].has_position_channels() {
+                        bvh_global.translation
+                    } else {
+                        rest.translation
+                    };
+
+                    Isometry3::from_parts(translation, rotation)
+                } else {
+                    rest
+                };
+                globals.push(global);
+
+                let local = parent_global.inverse() * global;
+                sample_poses[idx].push(from_matrix4(&local.to_homogeneous()));
+            }
+        }
+
+        let bone_animations = joint_names
+            .iter()
+            .zip(sample_poses)
+            .map(|(name, sample_poses)| collada::Animation {
+                target: format!("{}/transform", name),
+                sample_times: sample_times.clone(),
+                sample_poses,
+            })
+            .collect();
+
+        Ok(bone_animations)
+    }
+}
+
+fn isometry_from_transform(t: &Transform) -> Isometry3<f32> {
+    t.into()
+}
+
+fn isometry_from_matrix(m: &Matrix4<f32>) -> Isometry3<f32> {
+    isometry_from_transform(&m.into())
+}
+
+/// Compose the local transforms of the joints, parents being before their children
+fn global_transforms(parents: &[Option<usize>], locals: &[Isometry3<f32>]) -> Vec<Isometry3<f32>> {
+    let mut globals: Vec<Isometry3<f32>> = Vec::with_capacity(locals.len());
+    for (parent, local) in parents.iter().zip(locals.iter()) {
+        let global = if let Some(parent) = parent {
+            globals[*parent] * local
+        } else {
+            *local
+        };
+        globals.push(global);
+    }
+
+    globals
+}
+
+/// Local and global transforms of the skeleton joints in the rest pose
+fn rest_transforms(
+    skeleton: &Skeleton,
+    parents: &[Option<usize>],
+) -> (Vec<Isometry3<f32>>, Vec<Isometry3<f32>>) {
+    let rest_locals = skeleton
+        .get_rest_local_transforms()
+        .iter()
+        .map(isometry_from_matrix)
+        .collect::<Vec<_>>();
+    let rest_globals = global_transforms(parents, &rest_locals);
+
+    (rest_locals, rest_globals)
+}
+
+/// Decompose a rotation into ZXY euler angles in degrees,
+/// i.e. r = Rz(z) * Rx(x) * Ry(y)
+fn euler_zxy(r: &UnitQuaternion<f32>) -> (f32, f32, f32) {
+    let m = r.to_rotation_matrix();
+    let m = m.matrix();
+
+    let sx = m[(2, 1)].clamp(-1.0, 1.0);
+    let x = sx.asin();
+    let (z, y) = if sx.abs() < 0.999_999 {
+        ((-m[(0, 1)]).atan2(m[(1, 1)]), (-m[(2, 0)]).atan2(m[(2, 2)]))
+    } else {
+        // Gimbal lock, the Y rotation is merged into the Z one
+        (m[(1, 0)].atan2(m[(0, 0)]), 0.0)
+    };
+
+    (z.to_degrees(), x.to_degrees(), y.to_degrees())
+}
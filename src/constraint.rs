@@ -0,0 +1,156 @@
+use super::ik::JointConstraint;
+use super::pose::Pose;
+use super::skeleton::{BoneId, Skeleton};
+use super::transform::Transform;
+use super::Error;
+
+use na::{Isometry3, Matrix4, Point3, Unit, UnitQuaternion, Vector3};
+
+/// Length under which a vector is considered null
+const EPSILON: f32 = 1e-6;
+
+/// The kinds of constraints, the axes being given in the space of the constrained bone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstraintKind {
+    /// Point `axis` towards the target while keeping `up`
+    /// as close as possible to `world_up`, e.g. head and eye tracking
+    Aim {
+        axis: Unit<Vector3<f32>>,
+        up: Unit<Vector3<f32>>,
+        world_up: Unit<Vector3<f32>>,
+        target: Point3<f32>,
+    },
+    /// Point `axis` towards the target with the smallest rotation
+    DampedTrack {
+        axis: Unit<Vector3<f32>>,
+        target: Point3<f32>,
+    },
+    /// Take the model space rotation of another bone
+    CopyRotation { source: BoneId },
+    /// Take the model space transform of another bone
+    CopyTransform { source: BoneId },
+    /// Limit the local rotation of the bone
+    LimitRotation(JointConstraint),
+}
+
+/// A constraint applied on a bone of a sampled pose
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Constraint {
+    pub bone: BoneId,
+    pub kind: ConstraintKind,
+    /// Blend factor between the sampled pose (0.0) and the constrained one (1.0)
+    pub influence: f32,
+}
+
+impl Constraint {
+    /// A constraint with full influence on a named bone
+    ///
+    /// The source bones of the kind can be found with `Skeleton::find_bone`
+    pub fn new(skeleton: &Skeleton, bone_name: &str, kind: ConstraintKind) -> Result<Self, Error> {
+        let bone = skeleton
+            .find_bone(bone_name)
+            .ok_or_else(|| Error::BoneNotFound {
+                name: bone_name.to_owned(),
+            })?;
+
+        Ok(Constraint {
+            bone,
+            kind,
+            influence: 1.0,
+        })
+    }
+
+    /// Apply the constraint on the pose
+    pub fn apply(&self, skeleton: &Skeleton, pose: &mut Pose) {
+        let global = pose.model_isometry(skeleton, self.bone);
+        let position = Point3::from(global.translation.vector);
+
+        let constrained = match self.kind {
+            ConstraintKind::Aim {
+                axis,
+                up,
+                world_up,
+                target,
+            } => {
+                let rotation = track(&global.rotation, &axis, &(target - position));
+                let rotation = if let Some(forward) = Unit::try_new(target - position, EPSILON) {
+                    // Twist around the aiming direction to bring up towards the world up
+                    let project = |v: Vector3<f32>| v - forward.into_inner() * v.dot(&forward);
+                    let current_up = project(rotation * up.into_inner());
+                    let wanted_up = project(world_up.into_inner());
+                    if current_up.norm() > EPSILON && wanted_up.norm() > EPSILON {
+                        let twist = UnitQuaternion::rotation_between(&current_up, &wanted_up)
+                            .unwrap_or_else(|| {
+                                UnitQuaternion::from_axis_angle(&forward, std::f32::consts::PI)
+                            });
+                        twist * rotation
+                    } else {
+                        rotation
+                    }
+                } else {
+                    rotation
+                };
+
+                Isometry3::from_parts(global.translation, rotation)
+            }
+            ConstraintKind::DampedTrack { axis, target } => {
+                let rotation = track(&global.rotation, &axis, &(target - position));
+                Isometry3::from_parts(global.translation, rotation)
+            }
+            ConstraintKind::CopyRotation { source } => {
+                let source = pose.model_isometry(skeleton, source);
+                Isometry3::from_parts(global.translation, source.rotation)
+            }
+            ConstraintKind::CopyTransform { source } => pose.model_isometry(skeleton, source),
+            ConstraintKind::LimitRotation(limit) => {
+                let rest_local_transform: Transform = skeleton
+                    .get_bone(self.bone)
+                    .get_rest_local_transform()
+                    .into();
+                let local_transform = pose.get_local_transform(self.bone);
+                let rotation = limit.apply(
+                    rest_local_transform.get_rotation(),
+                    local_transform.get_rotation(),
+                );
+
+                let local_transform = local_transform.with_rotation(rotation);
+                pose.set_local_transform(self.bone, local_transform);
+                pose.model_isometry(skeleton, self.bone)
+            }
+        };
+
+        // Blend with the sampled transform
+        let global = global.lerp_slerp(&constrained, self.influence);
+
+        // Keep the scale of the bone
+        let model_transform: Transform = (&pose.model_matrix(skeleton, self.bone)).into();
+        let scale = Matrix4::new_nonuniform_scaling(model_transform.get_scale());
+        let parent = skeleton
+            .parent(self.bone)
+            .and_then(|parent| pose.model_matrix(skeleton, parent).try_inverse())
+            .unwrap_or_else(Matrix4::identity);
+        let local_transform = parent * global.to_homogeneous() * scale;
+        pose.set_local_transform(self.bone, (&local_transform).into());
+    }
+}
+
+/// Apply the constraints in order on a sampled pose
+pub fn apply_constraints(skeleton: &Skeleton, pose: &mut Pose, constraints: &[Constraint]) {
+    for constraint in constraints {
+        constraint.apply(skeleton, pose);
+    }
+}
+
+/// The smallest rotation pointing the axis of a bone towards a direction
+fn track(
+    rotation: &UnitQuaternion<f32>,
+    axis: &Unit<Vector3<f32>>,
+    direction: &Vector3<f32>,
+) -> UnitQuaternion<f32> {
+    let current = rotation * axis.into_inner();
+    if let Some(delta) = UnitQuaternion::rotation_between(&current, direction) {
+        delta * rotation
+    } else {
+        *rotation
+    }
+}
@@ -0,0 +1,408 @@
+use super::animation::Animations;
+use super::skeleton::{BoneId, Skeleton};
+use super::transform::Transform;
+use super::{Data, Error, Vertices};
+
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Component types of the glTF accessors
+const UNSIGNED_SHORT: u32 = 5123;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+// Targets of the glTF buffer views
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+// GLB container magic numbers
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_VERSION: u32 = 2;
+const GLB_CHUNK_JSON: u32 = 0x4E4F_534A;
+const GLB_CHUNK_BIN: u32 = 0x004E_4942;
+
+/// Pad the bytes to a 4 bytes boundary
+fn pad(bytes: &mut Vec<u8>, value: u8) {
+    let padding = (4 - bytes.len() % 4) % 4;
+    bytes.resize(bytes.len() + padding, value);
+}
+
+/// The binary chunk of the glTF file
+/// along with the buffer views and accessors describing it
+struct Buffer {
+    data: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Buffer {
+            data: vec![],
+            views: vec![],
+            accessors: vec![],
+        }
+    }
+
+    fn push_view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        // Every component type we write is 4 bytes aligned at most
+        pad(&mut self.data, 0);
+
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.data.len(),
+            "byteLength": bytes.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+
+        self.data.extend_from_slice(bytes);
+        self.views.push(view);
+        self.views.len() - 1
+    }
+
+    fn push_accessor(&mut self, accessor: Value) -> usize {
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    /// Push an accessor of floats
+    ///
+    /// `bounds` must be set for the accessors requiring
+    /// a min and a max (i.e. POSITION and animation inputs)
+    fn push_f32(
+        &mut self,
+        values: &[f32],
+        ty: &str,
+        num_components: usize,
+        target: Option<u32>,
+        bounds: bool,
+    ) -> usize {
+        let mut bytes = Vec::with_capacity(values.len() * 4);
+        for v in values {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        let view = self.push_view(&bytes, target);
+
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len() / num_components,
+            "type": ty,
+        });
+        if bounds {
+            let mut min = vec![f32::MAX; num_components];
+            let mut max = vec![f32::MIN; num_components];
+            for element in values.chunks(num_components) {
+                for (c, v) in element.iter().enumerate() {
+                    min[c] = min[c].min(*v);
+                    max[c] = max[c].max(*v);
+                }
+            }
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+
+        self.push_accessor(accessor)
+    }
+
+    fn push_u16(&mut self, values: &[u16], ty: &str, num_components: usize) -> usize {
+        let mut bytes = Vec::with_capacity(values.len() * 2);
+        for v in values {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        let view = self.push_view(&bytes, Some(ARRAY_BUFFER));
+
+        self.push_accessor(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_SHORT,
+            "count": values.len() / num_components,
+            "type": ty,
+        }))
+    }
+
+    fn push_indices(&mut self, indices: &[u32]) -> usize {
+        let mut bytes = Vec::with_capacity(indices.len() * 4);
+        for v in indices {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        let view = self.push_view(&bytes, Some(ELEMENT_ARRAY_BUFFER));
+
+        self.push_accessor(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": indices.len(),
+            "type": "SCALAR",
+        }))
+    }
+}
+
+fn push_mesh(vertices: &Vertices, buffer: &mut Buffer) -> Value {
+    let positions = vertices
+        .positions
+        .iter()
+        .flat_map(|p| vec![p.x, p.y, p.z])
+        .collect::<Vec<_>>();
+    let normals = vertices
+        .normals
+        .iter()
+        .flat_map(|n| vec![n.x, n.y, n.z])
+        .collect::<Vec<_>>();
+    // COLLADA texcoords have their origin at the bottom left corner
+    // whereas glTF ones start at the top left corner
+    let texcoords = vertices
+        .texcoords
+        .iter()
+        .flat_map(|t| vec![t.x, 1.0 - t.y])
+        .collect::<Vec<_>>();
+
+    let mut attributes = json!({
+        "POSITION": buffer.push_f32(&positions, "VEC3", 3, Some(ARRAY_BUFFER), true),
+        "NORMAL": buffer.push_f32(&normals, "VEC3", 3, Some(ARRAY_BUFFER), false),
+        "TEXCOORD_0": buffer.push_f32(&texcoords, "VEC2", 2, Some(ARRAY_BUFFER), false),
+    });
+
+    if let (Some(bone_ids), Some(weights)) = (&vertices.bone_ids, &vertices.weights) {
+        // glTF expects 4 influences per vertex, the two last ones are left empty
+        let joints = bone_ids
+            .iter()
+            .flat_map(|b| vec![b[0] as u16, b[1] as u16, 0, 0])
+            .collect::<Vec<_>>();
+        let weights = weights
+            .iter()
+            .flat_map(|w| {
+                // glTF requires normalized weights
+                let sum = w[0] + w[1];
+                if sum > 0.0 {
+                    vec![w[0] / sum, w[1] / sum, 0.0, 0.0]
+                } else {
+                    vec![1.0, 0.0, 0.0, 0.0]
+                }
+            })
+            .collect::<Vec<_>>();
+
+        attributes["JOINTS_0"] = json!(buffer.push_u16(&joints, "VEC4", 4));
+        attributes["WEIGHTS_0"] =
+            json!(buffer.push_f32(&weights, "VEC4", 4, Some(ARRAY_BUFFER), false));
+    }
+
+    let indices = buffer.push_indices(&vertices.indices);
+
+    json!({
+        "primitives": [{
+            "attributes": attributes,
+            "indices": indices,
+        }]
+    })
+}
+
+fn transform_to_json(node: &mut Value, transform: &Transform) {
+    let t = transform.get_translation();
+    let r = transform.get_rotation().quaternion();
+    let s = transform.get_scale();
+
+    node["translation"] = json!([t.x, t.y, t.z]);
+    node["rotation"] = json!([r.i, r.j, r.k, r.w]);
+    node["scale"] = json!([s.x, s.y, s.z]);
+}
+
+/// Create one node per joint, the index of the node being the index of the joint
+///
+/// Returns the nodes and the skin referencing the joints influencing the vertices
+fn push_skeleton(skeleton: &Skeleton, buffer: &mut Buffer) -> (Vec<Value>, Value) {
+    let bones = skeleton.get_bones();
+    let local_transforms = skeleton.get_rest_local_transforms();
+
+    // Skinning index -> (node, inverse bind pose)
+    let mut joints = vec![None; skeleton.get_num_vertices_attached_bones()];
+    let nodes = bones
+        .iter()
+        .map(|bone| {
+            let node_idx = bone.get_name_idx();
+            let local: Transform = (&local_transforms[node_idx]).into();
+
+            let mut node = json!({ "name": bone.get_name(skeleton) });
+            transform_to_json(&mut node, &local);
+            if !bone.get_children_idx().is_empty() {
+                node["children"] = json!(bone.get_children_idx());
+            }
+
+            if let Some(idx_transform) = bone.idx_transform {
+                joints[idx_transform] = Some((node_idx, *bone.get_inverse_bind_pose()));
+            }
+
+            node
+        })
+        .collect::<Vec<_>>();
+
+    let joints = joints.into_iter().flatten().collect::<Vec<_>>();
+    let inverse_bind_poses = joints
+        .iter()
+        .flat_map(|(_, m)| m.as_slice().to_vec())
+        .collect::<Vec<_>>();
+    let inverse_bind_poses = buffer.push_f32(&inverse_bind_poses, "MAT4", 16, None, false);
+
+    let mut skin = json!({
+        "joints": joints.iter().map(|(node, _)| *node).collect::<Vec<_>>(),
+        "inverseBindMatrices": inverse_bind_poses,
+    });
+    // The common root of the joints, only defined for single root skeletons
+    let roots = skeleton.get_roots().collect::<Vec<_>>();
+    if let [root] = roots.as_slice() {
+        skin["skeleton"] = json!(root.get_name_idx());
+    }
+
+    (nodes, skin)
+}
+
+/// Push the clips as linearly interpolated channels targeting
+/// the joint nodes
+fn push_animations(animations: &Animations, buffer: &mut Buffer) -> Vec<Value> {
+    // The offsets of the additive clips cannot be played on their own
+    let mut names = animations
+        .iter()
+        .filter(|(_, anim)| !anim.is_additive())
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let num_joints = animations.get_skeleton().get_joint_names().len();
+    names
+        .into_iter()
+        .map(|name| {
+            let anim = animations.get_animation(name).unwrap();
+
+            let input = buffer.push_f32(anim.get_times(), "SCALAR", 1, None, true);
+
+            let mut samplers = vec![];
+            let mut channels = vec![];
+            for joint_idx in 0..num_joints {
+                let track = anim.get_track(BoneId(joint_idx));
+                let translations = track
+                    .get_translations()
+                    .iter()
+                    .flat_map(|t| vec![t.x, t.y, t.z])
+                    .collect::<Vec<_>>();
+                let rotations = track
+                    .get_rotations()
+                    .iter()
+                    .flat_map(|r| {
+                        let r = r.quaternion();
+                        vec![r.i, r.j, r.k, r.w]
+                    })
+                    .collect::<Vec<_>>();
+                let scales = track
+                    .get_scales()
+                    .iter()
+                    .flat_map(|s| vec![s.x, s.y, s.z])
+                    .collect::<Vec<_>>();
+
+                for (path, output) in &[
+                    (
+                        "translation",
+                        buffer.push_f32(&translations, "VEC3", 3, None, false),
+                    ),
+                    (
+                        "rotation",
+                        buffer.push_f32(&rotations, "VEC4", 4, None, false),
+                    ),
+                    ("scale", buffer.push_f32(&scales, "VEC3", 3, None, false)),
+                ] {
+                    channels.push(json!({
+                        "sampler": samplers.len(),
+                        "target": {
+                            "node": joint_idx,
+                            "path": path,
+                        },
+                    }));
+                    samplers.push(json!({
+                        "input": input,
+                        "output": output,
+                        "interpolation": "LINEAR",
+                    }));
+                }
+            }
+
+            json!({
+                "name": name,
+                "samplers": samplers,
+                "channels": channels,
+            })
+        })
+        .collect()
+}
+
+/// Write the vertices, the skeleton and all the animation clips
+/// to a binary glTF 2.0 file (.glb)
+///
+/// The additive clips are skipped, glTF has no notion of additive animation
+pub fn write_gltf<P: AsRef<Path>>(data: &Data, path: P) -> Result<(), Error> {
+    let mut buffer = Buffer::new();
+    // The joint nodes come first so that their indices
+    // match the joint indices, followed by the mesh node
+    let mut nodes = vec![];
+    let mut scene_nodes = vec![];
+
+    let mesh = push_mesh(&data.vertices, &mut buffer);
+    let mut mesh_node = json!({ "mesh": 0 });
+
+    let mut gltf = json!({
+        "asset": {
+            "version": "2.0",
+            "generator": "rib",
+        },
+        "scene": 0,
+        "meshes": [mesh],
+    });
+
+    if let Some(animations) = &data.animations {
+        let skeleton = animations.get_skeleton();
+        let (joint_nodes, skin) = push_skeleton(skeleton, &mut buffer);
+        nodes = joint_nodes;
+        scene_nodes.extend(skeleton.get_roots().map(|root| root.get_name_idx()));
+
+        gltf["skins"] = json!([skin]);
+        gltf["animations"] = json!(push_animations(animations, &mut buffer));
+        mesh_node["skin"] = json!(0);
+    }
+
+    scene_nodes.push(nodes.len());
+    nodes.push(mesh_node);
+
+    let Buffer {
+        mut data,
+        views,
+        accessors,
+    } = buffer;
+    pad(&mut data, 0);
+
+    gltf["nodes"] = json!(nodes);
+    gltf["scenes"] = json!([{ "nodes": scene_nodes }]);
+    gltf["buffers"] = json!([{ "byteLength": data.len() }]);
+    gltf["bufferViews"] = json!(views);
+    gltf["accessors"] = json!(accessors);
+
+    let mut gltf = serde_json::to_vec(&gltf).map_err(Error::Json)?;
+    // The JSON chunk must be padded with spaces
+    pad(&mut gltf, b' ');
+
+    let length = 12 + 8 + gltf.len() + 8 + data.len();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&GLB_MAGIC.to_le_bytes())?;
+    file.write_all(&GLB_VERSION.to_le_bytes())?;
+    file.write_all(&(length as u32).to_le_bytes())?;
+
+    file.write_all(&(gltf.len() as u32).to_le_bytes())?;
+    file.write_all(&GLB_CHUNK_JSON.to_le_bytes())?;
+    file.write_all(&gltf)?;
+
+    file.write_all(&(data.len() as u32).to_le_bytes())?;
+    file.write_all(&GLB_CHUNK_BIN.to_le_bytes())?;
+    file.write_all(&data)?;
+    file.flush()?;
+
+    Ok(())
+}
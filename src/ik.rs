@@ -0,0 +1,294 @@
+use super::pose::Pose;
+use super::skeleton::{BoneId, Skeleton};
+use super::transform::Transform;
+
+use na::{Point3, Quaternion, Unit, UnitQuaternion, Vector3};
+
+/// Length under which a vector is considered null
+const EPSILON: f32 = 1e-6;
+
+/// Limits of the rotation of a joint relative to its rest rotation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JointConstraint {
+    /// Rotation around `axis`, given in the space of the joint,
+    /// by an angle between `min` and `max` radians, e.g. a knee
+    Hinge {
+        axis: Unit<Vector3<f32>>,
+        min: f32,
+        max: f32,
+    },
+    /// Rotation by at most `max_angle` radians in any direction, e.g. a shoulder
+    Cone { max_angle: f32 },
+}
+
+impl JointConstraint {
+    /// Bring a local rotation back within the limits
+    pub fn apply(
+        &self,
+        rest_rotation: &UnitQuaternion<f32>,
+        rotation: &UnitQuaternion<f32>,
+    ) -> UnitQuaternion<f32> {
+        let delta = rest_rotation.inverse() * rotation;
+        let delta = match *self {
+            JointConstraint::Hinge { axis, min, max } => {
+                // Only keep the twist of the rotation around the axis
+                let q = delta.quaternion();
+                let twist = Quaternion::from_parts(q.w, axis.into_inner() * q.imag().dot(&axis));
+                let angle = if twist.norm() > EPSILON {
+                    UnitQuaternion::new_normalize(twist)
+                        .scaled_axis()
+                        .dot(&axis)
+                } else {
+                    0.0
+                };
+
+                UnitQuaternion::from_axis_angle(&axis, angle.clamp(min, max))
+            }
+            JointConstraint::Cone { max_angle } => {
+                let angle = delta.angle();
+                if angle > max_angle {
+                    UnitQuaternion::identity().slerp(&delta, max_angle / angle)
+                } else {
+                    delta
+                }
+            }
+        };
+
+        rest_rotation * delta
+    }
+}
+
+/// A chain of joints going from a root joint down to an end effector
+#[derive(Debug, Clone, PartialEq)]
+pub struct IkChain {
+    // From the root of the chain down to the end effector
+    joints: Vec<BoneId>,
+    constraints: Vec<Option<JointConstraint>>,
+}
+
+impl IkChain {
+    /// The chain from `root` down to `end`, None if `end` is not below `root`
+    pub fn new(skeleton: &Skeleton, root: BoneId, end: BoneId) -> Option<Self> {
+        let mut joints = vec![end];
+        if root != end {
+            for ancestor in skeleton.ancestors(end) {
+                joints.push(ancestor);
+                if ancestor == root {
+                    break;
+                }
+            }
+        }
+        if *joints.last().unwrap() != root || joints.len() < 2 {
+            return None;
+        }
+        joints.reverse();
+
+        let constraints = vec![None; joints.len()];
+        Some(IkChain {
+            joints,
+            constraints,
+        })
+    }
+
+    /// Limit the rotation of a joint of the chain, None if the bone is not part of it
+    pub fn set_constraint(&mut self, id: BoneId, constraint: JointConstraint) -> Option<()> {
+        let idx = self.joints.iter().position(|joint| *joint == id)?;
+        self.constraints[idx] = Some(constraint);
+        Some(())
+    }
+
+    pub fn get_joints(&self) -> &[BoneId] {
+        &self.joints
+    }
+
+    /// Cyclic coordinate descent
+    ///
+    /// Starting from the end of the chain, each joint is rotated so that
+    /// the end effector points towards the target. Returns whether the end
+    /// effector is within `tolerance` of the target.
+    pub fn solve_ccd(
+        &self,
+        skeleton: &Skeleton,
+        pose: &mut Pose,
+        target: &Point3<f32>,
+        max_iterations: usize,
+        tolerance: f32,
+    ) -> bool {
+        let end = *self.joints.last().unwrap();
+        for _ in 0..max_iterations {
+            if (position(skeleton, pose, end) - target).norm() <= tolerance {
+                return true;
+            }
+
+            for (idx, joint) in self.joints.iter().enumerate().rev().skip(1) {
+                let joint_position = position(skeleton, pose, *joint);
+                let end_position = position(skeleton, pose, end);
+                if let Some(rotation) = UnitQuaternion::rotation_between(
+                    &(end_position - joint_position),
+                    &(target - joint_position),
+                ) {
+                    rotate_joint(skeleton, pose, *joint, &rotation);
+                    self.constrain(skeleton, pose, idx);
+                }
+            }
+        }
+
+        (position(skeleton, pose, end) - target).norm() <= tolerance
+    }
+
+    /// Forward and backward reaching inverse kinematics
+    ///
+    /// The joint positions are solved first keeping the bone lengths, then
+    /// the joints are rotated towards them and constrained. Returns whether the
+    /// end effector is within `tolerance` of the target.
+    pub fn solve_fabrik(
+        &self,
+        skeleton: &Skeleton,
+        pose: &mut Pose,
+        target: &Point3<f32>,
+        max_iterations: usize,
+        tolerance: f32,
+    ) -> bool {
+        let mut positions = self
+            .joints
+            .iter()
+            .map(|joint| position(skeleton, pose, *joint))
+            .collect::<Vec<_>>();
+        let lengths = positions
+            .windows(2)
+            .map(|p| (p[1] - p[0]).norm())
+            .collect::<Vec<_>>();
+        let root = positions[0];
+        let num_joints = positions.len();
+
+        for _ in 0..max_iterations {
+            if (positions[num_joints - 1] - target).norm() <= tolerance {
+                break;
+            }
+
+            // Backward pass, from the target up to the root
+            positions[num_joints - 1] = *target;
+            for idx in (0..num_joints - 1).rev() {
+                let direction = (positions[idx] - positions[idx + 1])
+                    .try_normalize(EPSILON)
+                    .unwrap_or_else(Vector3::zeros);
+                positions[idx] = positions[idx + 1] + direction * lengths[idx];
+            }
+
+            // Forward pass, from the root down to the end effector
+            positions[0] = root;
+            for idx in 0..num_joints - 1 {
+                let direction = (positions[idx + 1] - positions[idx])
+                    .try_normalize(EPSILON)
+                    .unwrap_or_else(Vector3::zeros);
+                positions[idx + 1] = positions[idx] + direction * lengths[idx];
+            }
+        }
+
+        for idx in 0..num_joints - 1 {
+            let joint_position = position(skeleton, pose, self.joints[idx]);
+            let child_position = position(skeleton, pose, self.joints[idx + 1]);
+            if let Some(rotation) = UnitQuaternion::rotation_between(
+                &(child_position - joint_position),
+                &(positions[idx + 1] - joint_position),
+            ) {
+                rotate_joint(skeleton, pose, self.joints[idx], &rotation);
+                self.constrain(skeleton, pose, idx);
+            }
+        }
+
+        let end = *self.joints.last().unwrap();
+        (position(skeleton, pose, end) - target).norm() <= tolerance
+    }
+
+    fn constrain(&self, skeleton: &Skeleton, pose: &mut Pose, idx: usize) {
+        if let Some(constraint) = &self.constraints[idx] {
+            let id = self.joints[idx];
+            let rest_local_transform: Transform =
+                skeleton.get_bone(id).get_rest_local_transform().into();
+            let local_transform = pose.get_local_transform(id);
+
+            let rotation = constraint.apply(
+                rest_local_transform.get_rotation(),
+                local_transform.get_rotation(),
+            );
+            let local_transform = local_transform.with_rotation(rotation);
+            pose.set_local_transform(id, local_transform);
+        }
+    }
+}
+
+/// Analytic inverse kinematics of the two bones above `end`, e.g. a leg or an arm
+///
+/// The middle joint bends towards the `pole` position, e.g. in front of the knee.
+/// Returns whether the target is within reach, otherwise the limb is stretched
+/// towards it. None if `end` does not have two ancestors.
+pub fn solve_two_bone(
+    skeleton: &Skeleton,
+    pose: &mut Pose,
+    end: BoneId,
+    target: &Point3<f32>,
+    pole: &Point3<f32>,
+) -> Option<bool> {
+    let mid = skeleton.parent(end)?;
+    let root = skeleton.parent(mid)?;
+
+    let a = position(skeleton, pose, root);
+    let b = position(skeleton, pose, mid);
+    let c = position(skeleton, pose, end);
+    let lab = (b - a).norm();
+    let lcb = (c - b).norm();
+    if lab < EPSILON || lcb < EPSILON {
+        return Some(false);
+    }
+    let distance = (target - a).norm();
+    let lat = distance.clamp(EPSILON, lab + lcb - EPSILON);
+
+    // Bend the middle joint so that the end is at the distance of the target
+    let ba_bc_0 = (a - b).angle(&(c - b));
+    let ba_bc_1 = ((lat * lat - lab * lab - lcb * lcb) / (-2.0 * lab * lcb))
+        .clamp(-1.0, 1.0)
+        .acos();
+    let axis = Unit::try_new((a - b).cross(&(c - b)), EPSILON)
+        .or_else(|| Unit::try_new((c - a).cross(&(pole - a)), EPSILON));
+    if let Some(axis) = axis {
+        let rotation = UnitQuaternion::from_axis_angle(&axis, ba_bc_1 - ba_bc_0);
+        rotate_joint(skeleton, pose, mid, &rotation);
+    }
+
+    // Point the limb towards the target
+    let c = position(skeleton, pose, end);
+    if let Some(rotation) = UnitQuaternion::rotation_between(&(c - a), &(target - a)) {
+        rotate_joint(skeleton, pose, root, &rotation);
+    }
+
+    // Twist the limb around its axis so that the middle joint faces the pole
+    if let Some(axis) = Unit::try_new(target - a, EPSILON) {
+        let project = |v: Vector3<f32>| v - axis.into_inner() * v.dot(&axis);
+        let b = position(skeleton, pose, mid);
+        let (mid_direction, pole_direction) = (project(b - a), project(pole - a));
+        if mid_direction.norm() > EPSILON && pole_direction.norm() > EPSILON {
+            let rotation = UnitQuaternion::rotation_between(&mid_direction, &pole_direction)
+                .unwrap_or_else(|| UnitQuaternion::from_axis_angle(&axis, std::f32::consts::PI));
+            rotate_joint(skeleton, pose, root, &rotation);
+        }
+    }
+
+    Some(distance <= lab + lcb)
+}
+
+/// The model space position of a joint
+fn position(skeleton: &Skeleton, pose: &Pose, id: BoneId) -> Point3<f32> {
+    pose.model_isometry(skeleton, id).translation.vector.into()
+}
+
+/// Apply a rotation given in model space to a joint
+fn rotate_joint(skeleton: &Skeleton, pose: &mut Pose, id: BoneId, rotation: &UnitQuaternion<f32>) {
+    let global_rotation = pose.model_isometry(skeleton, id).rotation;
+    let local_transform = pose.get_local_transform(id);
+
+    let local_rotation =
+        local_transform.get_rotation() * global_rotation.inverse() * rotation * global_rotation;
+    let local_transform = local_transform.with_rotation(local_rotation);
+    pose.set_local_transform(id, local_transform);
+}
@@ -1,13 +1,35 @@
 extern crate nalgebra as na;
 
 mod animation;
+mod blend;
+mod bvh;
+mod constraint;
+mod gltf;
+mod ik;
+mod mask;
+mod obj;
+mod pose;
+mod prune;
+mod retarget;
 mod skeleton;
+mod state_machine;
 mod transform;
 mod utils;
 mod vertices;
 
-pub use animation::Animations;
-pub use vertices::Vertices;
+pub use animation::{AdditiveReference, Animation, AnimationEvent, Animations, Track, WrapMode};
+pub use blend::{BlendSpace1D, BlendSpace2D, Crossfade};
+pub use constraint::{apply_constraints, Constraint, ConstraintKind};
+pub use gltf::write_gltf;
+pub use ik::{solve_two_bone, IkChain, JointConstraint};
+pub use mask::BoneMask;
+pub use obj::{write_obj, write_posed_obj, write_posed_obj_sequence};
+pub use pose::Pose;
+pub use prune::{prune_bones, BoneRemap};
+pub use skeleton::{Bone, BoneId, BoneNode, BreadthFirst, DepthFirst, Skeleton};
+pub use state_machine::{Condition, Motion, Parameter, State, StateMachine, Transition};
+pub use transform::Transform;
+pub use vertices::{SubMesh, Vertices};
 
 use na::{Point2, Point3, Vector3};
 use std::io;
@@ -16,12 +38,27 @@ use std::path::Path;
 pub enum Error {
     OpenFile { path: String },
     EmptyFile,
+    MissingFileStem { path: String },
+    InvalidFrameTime { frame_time: f32 },
+    InvalidEventTime { time: f32 },
+    InvalidMaxBones { max_bones: usize },
     PrimitiveNotTriangles,
     SkeletonNotEqual,
+    MaskNotMatching,
     VerticesNotEqual,
+    AnimationNotFound { name: String },
+    AdditiveAnimation { name: String },
+    NotAdditiveAnimation { name: String },
+    BoneNotFound { name: String },
+    JointNotMatched { name: String },
+    EmptyBlendSpace,
+    StateNotFound { name: String },
+    RootMotionAlreadyExtracted,
+    Bvh { line: usize, message: String },
     IoError(io::Error),
     Serialize(Box<bincode::ErrorKind>),
     Deserialize(Box<bincode::ErrorKind>),
+    Json(serde_json::Error),
 }
 
 impl<'a> From<io::Error> for Error {
@@ -268,7 +305,7 @@ fn parse_collada_doc<'a, P: AsRef<Path> + std::fmt::Debug + 'a>(
         };
 
         if let Some(name) = path.as_ref().file_stem() {
-            let animations = Animations::new(name.to_str().unwrap(), &doc, frame_time);
+            let animations = Animations::new(name.to_str().unwrap(), &doc, frame_time)?;
             Ok(Data {
                 vertices,
                 animations,
@@ -338,4 +375,1107 @@ mod tests {
         super::write(&model, "./test/spider/spider.bin").unwrap();
         let Data { animations: _, .. } = super::read(&"./test/spider/spider.bin").unwrap();
     }
+
+    #[test]
+    fn flat_skeleton() {
+        let model = super::load(&"./test/spider", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        // Parents are stored before their children
+        for (idx, bone) in skeleton.get_bones().iter().enumerate() {
+            assert_eq!(bone.get_name_idx(), idx);
+            if let Some(parent) = bone.get_parent_idx() {
+                assert!(parent < idx);
+            }
+        }
+
+        // The tree views reach every bone
+        fn count(node: super::BoneNode) -> usize {
+            1 + node.get_children().map(count).sum::<usize>()
+        }
+        let num_bones = skeleton.get_roots().map(count).sum::<usize>();
+        assert_eq!(num_bones, skeleton.get_bones().len());
+    }
+
+    #[test]
+    fn skin_joints() {
+        let model = super::load(&"./test/spider", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        // The joints of the skin are referenced by sid
+        let thorax = skeleton.find_bone("Spider_Armature_thorax").unwrap();
+        assert!(skeleton.get_bone(thorax).has_vertices_attached());
+        let root = skeleton.find_bone("Spider_Armature_root").unwrap();
+        assert!(!skeleton.get_bone(root).has_vertices_attached());
+
+        let mut skinning_indices = skeleton
+            .get_bones()
+            .iter()
+            .filter_map(|b| b.idx_transform)
+            .collect::<Vec<_>>();
+        skinning_indices.sort_unstable();
+        assert_eq!(
+            skinning_indices,
+            (0..skeleton.get_num_vertices_attached_bones()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn skeleton_queries() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        let hips = skeleton.find_bone("Human_Armature_Hips").unwrap();
+        let hand = skeleton.find_bone("Human_Armature_LeftHand").unwrap();
+        assert!(skeleton.find_bone("Tail").is_none());
+
+        assert!(skeleton.ancestors(hand).any(|id| id == hips));
+        assert!(skeleton.descendants(hips).any(|id| id == hand));
+        let forearm = skeleton.parent(hand).unwrap();
+        assert!(skeleton.children(forearm).any(|id| id == hand));
+
+        let num_bones = skeleton.get_bones().len();
+        assert_eq!(skeleton.depth_first().count(), num_bones);
+        assert_eq!(skeleton.breadth_first().count(), num_bones);
+        assert_eq!(
+            skeleton.breadth_first().next(),
+            skeleton.get_root().map(|root| root.get_id())
+        );
+
+        for id in skeleton.depth_first() {
+            if let Some(idx_transform) = skeleton.skinning_idx(id) {
+                assert_eq!(skeleton.bone_from_skinning_idx(idx_transform), Some(id));
+            }
+        }
+
+        // The bind transforms invert the inverse bind poses, and match the rest
+        // transforms for the bones without vertices
+        for bone in skeleton.get_bones() {
+            let bind_transform = bone.get_bind_transform();
+            let identity = bind_transform * bone.get_inverse_bind_pose();
+            assert!((identity - na::Matrix4::identity()).norm() < 1e-4);
+            if !bone.has_vertices_attached() {
+                assert!((bind_transform - bone.get_rest_transform()).norm() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn rest_pose() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        let bind_pose = skeleton.bind_pose();
+        assert_eq!(bind_pose.len(), skeleton.get_num_vertices_attached_bones());
+
+        // The global rest transforms are the composition of the local ones
+        for bone in skeleton.get_bones() {
+            let parent_transform = bone
+                .get_parent_idx()
+                .map(|p| *skeleton.get_bones()[p].get_rest_transform())
+                .unwrap_or_else(na::Matrix4::identity);
+            let rest_transform = parent_transform * bone.get_rest_local_transform();
+            assert!((rest_transform - bone.get_rest_transform()).norm() < 1e-4);
+        }
+
+        let forearm = skeleton.find_bone("Human_Armature_LeftForeArm").unwrap();
+        assert!(skeleton.get_bone_length(forearm) > 0.0);
+    }
+
+    #[test]
+    fn sample_close_keys() {
+        use super::{Animation, BoneId};
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        // Only the first joint is animated, by keys closer than the frame time
+        let sample_poses = (0..5)
+            .map(|i| {
+                let mut m = [[0.0; 4]; 4];
+                for (k, row) in m.iter_mut().enumerate() {
+                    row[k] = 1.0;
+                }
+                m[0][3] = i as f32;
+                m
+            })
+            .collect();
+        let bone_animation = collada::Animation {
+            target: format!("{}/transform", skeleton.get_joint_names()[0]),
+            sample_times: vec![0.0, 0.01, 0.02, 0.5, 0.51],
+            sample_poses,
+        };
+        let anim = Animation::new(skeleton, vec![bone_animation], 1.0 / 30.0);
+        let translations = anim
+            .get_sampled_local_transforms()
+            .map(|(_, local_transforms)| local_transforms[0].get_translation().x)
+            .collect::<Vec<_>>();
+
+        // The keys passed within a frame are skipped instead of being extrapolated
+        let expected = 2.0 + (1.0 / 30.0 - 0.02) / 0.48;
+        assert!((translations[1] - expected).abs() < 1e-3);
+        // The last frame is the last key
+        assert!((translations.last().unwrap() - 4.0).abs() < 1e-5);
+
+        // The joints without animation keep their rest transform
+        let rest = skeleton.get_bone(BoneId(1)).get_rest_local_transform();
+        let (_, local_transforms) = anim.get_sampled_local_transforms().next().unwrap();
+        let local_transform: na::Matrix4<f32> = local_transforms[1].clone().into();
+        assert!((local_transform - rest).norm() < 1e-4);
+    }
+
+    #[test]
+    fn bone_sockets() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        let pose = anims.query_model_pose("walk", 0.5).unwrap();
+        assert_eq!(pose.len(), skeleton.get_bones().len());
+
+        let hand = skeleton.find_bone("Human_Armature_LeftHand").unwrap();
+        let transform = anims
+            .query_bone_transform("walk", 0.5, "Human_Armature_LeftHand")
+            .unwrap();
+        assert!((transform - pose[hand.0]).norm() < 1e-4);
+
+        assert!(anims.query_bone_transform("walk", 0.5, "Sword").is_err());
+        assert!(anims.query_model_pose("fly", 0.5).is_err());
+    }
+
+    #[test]
+    fn human_gltf() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let path = std::env::temp_dir().join("human.glb");
+        super::write_gltf(&model, &path).unwrap();
+
+        // The GLB header and the chunks cover the whole file
+        let bytes = std::fs::read(&path).unwrap();
+        let read_u32 = |offset: usize| {
+            let mut word = [0; 4];
+            word.copy_from_slice(&bytes[offset..offset + 4]);
+            u32::from_le_bytes(word) as usize
+        };
+        assert_eq!(&bytes[0..4], b"glTF");
+        assert_eq!(read_u32(4), 2);
+        assert_eq!(read_u32(8), bytes.len());
+        let json_length = read_u32(12);
+        assert_eq!(&bytes[16..20], b"JSON");
+        let bin_offset = 20 + json_length;
+        assert_eq!(&bytes[bin_offset + 4..bin_offset + 8], b"BIN\0");
+        assert_eq!(bin_offset + 8 + read_u32(bin_offset), bytes.len());
+
+        // A node per joint and one for the mesh, a skin, and the accessors of
+        // the mesh, of the inverse bind poses and of the TRS channels of each clip
+        let gltf: serde_json::Value = serde_json::from_slice(&bytes[20..bin_offset]).unwrap();
+        let anims = model.animations.as_ref().unwrap();
+        let skeleton = anims.get_skeleton();
+        let num_joints = skeleton.get_bones().len();
+        let num_clips = anims.iter().count();
+        assert_eq!(gltf["nodes"].as_array().unwrap().len(), num_joints + 1);
+        assert_eq!(gltf["skins"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            gltf["skins"][0]["joints"].as_array().unwrap().len(),
+            skeleton.get_num_vertices_attached_bones()
+        );
+        assert_eq!(
+            gltf["accessors"].as_array().unwrap().len(),
+            6 + 1 + num_clips * (1 + 3 * num_joints)
+        );
+
+        let accessor_count = |attribute: &str| {
+            let idx = gltf["meshes"][0]["primitives"][0]["attributes"][attribute]
+                .as_u64()
+                .unwrap() as usize;
+            gltf["accessors"][idx]["count"].as_u64().unwrap() as usize
+        };
+        let num_vertices = model.vertices.positions.len();
+        assert_eq!(accessor_count("POSITION"), num_vertices);
+        assert_eq!(accessor_count("JOINTS_0"), num_vertices);
+        assert_eq!(accessor_count("WEIGHTS_0"), num_vertices);
+    }
+
+    #[test]
+    fn human_obj() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let dir = std::env::temp_dir();
+
+        super::write_obj(&model.vertices, dir.join("human.obj")).unwrap();
+        super::write_posed_obj_sequence(&model, "walk", 0.0, 1.0, 0.1, dir.join("walk.obj"))
+            .unwrap();
+        assert!(super::write_posed_obj(&model, "fly", 0.0, dir.join("fly.obj")).is_err());
+        assert!(super::write_posed_obj_sequence(
+            &model,
+            "walk",
+            0.0,
+            1.0,
+            0.0,
+            dir.join("walk.obj")
+        )
+        .is_err());
+        assert!(super::write_posed_obj_sequence(&model, "walk", 0.0, 1.0, 0.1, "..").is_err());
+
+        // A vertex without weights stays at its bind position
+        let mut vertices = model.vertices.clone();
+        vertices.weights.as_mut().unwrap()[0] = [0.0; 2];
+        let mut transforms = vec![];
+        model
+            .animations
+            .as_ref()
+            .unwrap()
+            .query("walk", 0.5, &mut transforms)
+            .unwrap();
+        let (positions, normals) = vertices.skin(&transforms);
+        assert_eq!(positions[0], vertices.positions[0]);
+        assert!(normals[0].iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn human_bvh() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+
+        let joint_map = ["Hips", "Spine", "LeftUpLeg"]
+            .iter()
+            .map(|name| (name.to_string(), format!("Human_Armature_{}", name)))
+            .collect();
+        anims
+            .append_bvh("sway", "./test/bvh/sway.bvh", &joint_map, 30.0)
+            .unwrap();
+        assert!(anims.get_animation("sway").is_some());
+        let mut transforms = vec![];
+        anims.query("sway", 0.05, &mut transforms).unwrap();
+
+        // The sampling rate must be positive
+        for fps in [0.0, -30.0, f32::NAN].iter() {
+            let result = anims.append_bvh("bad", "./test/bvh/sway.bvh", &joint_map, *fps);
+            assert!(matches!(result, Err(super::Error::InvalidFrameTime { .. })));
+        }
+        assert!(anims.get_animation("bad").is_none());
+    }
+
+    #[test]
+    fn human_bvh_export() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+
+        let path = std::env::temp_dir().join("walk.bvh");
+        anims.write_bvh("walk", &path).unwrap();
+        // The exported joints have the names of the skeleton joints
+        anims
+            .append_bvh("walk_bvh", &path, &Default::default(), 30.0)
+            .unwrap();
+        assert!(anims.write_bvh("fly", &path).is_err());
+
+        // The reimported clip plays the same local translations and rotations,
+        // BVH carries no scale
+        for time in [0.0, 0.25, 0.5, 0.8].iter() {
+            let walk = anims.query_pose("walk", *time).unwrap();
+            let walk_bvh = anims.query_pose("walk_bvh", *time).unwrap();
+            for (a, b) in walk
+                .get_local_transforms()
+                .iter()
+                .zip(walk_bvh.get_local_transforms().iter())
+            {
+                assert!((a.get_translation() - b.get_translation()).norm() < 1e-3);
+                assert!(a.get_rotation().angle_to(b.get_rotation()) < 1e-3);
+            }
+        }
+
+        // The frames are written in the order of the hierarchy
+        let written = super::bvh::Bvh::from_path(&path).unwrap();
+        let converted = super::bvh::Bvh::from_animation(
+            anims.get_skeleton(),
+            anims.get_animation("walk").unwrap(),
+        )
+        .unwrap();
+        let names = written
+            .joints
+            .iter()
+            .map(|joint| joint.name.clone())
+            .collect::<Vec<_>>();
+        let expected_names = converted
+            .hierarchy_order()
+            .iter()
+            .map(|idx| converted.joints[*idx].name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected_names);
+        assert_eq!(names[0], anims.get_skeleton().get_joint_names()[0]);
+        let (written_frame, frame) = (&written.frames[1], &converted.frames[1]);
+        for (written_idx, idx) in converted.hierarchy_order().iter().enumerate() {
+            for c in 0..6 {
+                assert!((written_frame[6 * written_idx + c] - frame[6 * idx + c]).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn retarget_human_walk() {
+        let human = super::load(&"./test/human", 30.0).unwrap();
+        let mut human_anims = human.animations.unwrap();
+
+        // Retargeting onto the same skeleton gives back the same clips
+        let anims = human_anims
+            .retarget(human_anims.get_skeleton(), &Default::default())
+            .unwrap();
+        let (mut walk, mut retargeted_walk) = (vec![], vec![]);
+        human_anims.query("walk", 0.5, &mut walk).unwrap();
+        anims.query("walk", 0.5, &mut retargeted_walk).unwrap();
+        for (m, retargeted) in walk.iter().zip(retargeted_walk.iter()) {
+            assert!((m - retargeted).norm() < 1e-3);
+        }
+
+        let joint_map = [("Human_Armature_Hips".to_string(), "Hips".to_string())]
+            .iter()
+            .cloned()
+            .collect();
+        assert!(human_anims
+            .retarget(human_anims.get_skeleton(), &joint_map)
+            .is_err());
+
+        human_anims.append(anims);
+    }
+
+    #[test]
+    fn split_palettes() {
+        let model = super::load(&"./test/spider", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let mut transforms = vec![];
+        anims.query("walk", 0.5, &mut transforms).unwrap();
+        let (positions, _) = model.vertices.skin(&transforms);
+
+        assert!(matches!(
+            model.vertices.split_palettes(5),
+            Err(super::Error::InvalidMaxBones { max_bones: 5 })
+        ));
+        let submeshes = model.vertices.split_palettes(8).unwrap();
+        assert!(submeshes.len() > 1);
+
+        let mut num_indices = 0;
+        let mut palette = vec![];
+        for submesh in &submeshes {
+            assert!(submesh.palette.len() <= 8);
+            num_indices += submesh.vertices.indices.len();
+
+            // Skinning with the palette gives the same positions
+            submesh.fill_palette(&transforms, &mut palette);
+            let (submesh_positions, _) = submesh.vertices.skin(&palette);
+            for p in submesh_positions {
+                assert!(positions.iter().any(|q| (p - q).norm() < 1e-3));
+            }
+        }
+        assert_eq!(num_indices, model.vertices.indices.len());
+
+        // A triangle without weights still gets a bone in its palette
+        let mut vertices = model.vertices.clone();
+        for &idx in &vertices.indices[..3] {
+            vertices.weights.as_mut().unwrap()[idx as usize] = [0.0, 0.0];
+        }
+        vertices.indices.truncate(3);
+        let submeshes = vertices.split_palettes(8).unwrap();
+        assert_eq!(submeshes.len(), 1);
+        for bone_ids in submeshes[0].vertices.bone_ids.as_ref().unwrap() {
+            assert!(bone_ids
+                .iter()
+                .all(|&b| (b as usize) < submeshes[0].palette.len()));
+        }
+    }
+
+    #[test]
+    fn inverse_kinematics() {
+        use super::{IkChain, JointConstraint, Pose};
+        use na::Point3;
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+        let position = |pose: &Pose, name: &str| {
+            let id = skeleton.find_bone(name).unwrap();
+            let m = pose.model_transforms(skeleton)[id.0];
+            Point3::new(m[(0, 3)], m[(1, 3)], m[(2, 3)])
+        };
+
+        // Move the foot closer to the hips
+        let mut pose = anims.query_pose("walk", 0.5).unwrap();
+        let hips = position(&pose, "Human_Armature_LeftUpLeg");
+        let foot = position(&pose, "Human_Armature_LeftFoot");
+        let knee = position(&pose, "Human_Armature_LeftLeg");
+        let target = hips + (foot - hips) * 0.8;
+        let foot_id = skeleton.find_bone("Human_Armature_LeftFoot").unwrap();
+        assert!(super::solve_two_bone(
+            skeleton,
+            &mut pose,
+            foot_id,
+            &target,
+            &(knee + (knee - hips)),
+        )
+        .unwrap());
+        assert!((position(&pose, "Human_Armature_LeftFoot") - target).norm() < 1e-3);
+        let mut transforms = vec![];
+        anims.query("walk", 0.5, &mut transforms).unwrap();
+        assert_eq!(pose.skinning_transforms(skeleton).len(), transforms.len());
+
+        // Bend the arm so that the hand gets closer to the shoulder
+        let shoulder = skeleton.find_bone("Human_Armature_LeftArm").unwrap();
+        let hand = skeleton.find_bone("Human_Armature_LeftHand").unwrap();
+        let mut chain = IkChain::new(skeleton, shoulder, hand).unwrap();
+        let cone = JointConstraint::Cone { max_angle: 3.0 };
+        assert!(chain.set_constraint(shoulder, cone).is_some());
+        assert!(chain.set_constraint(foot_id, cone).is_none());
+        assert!(IkChain::new(skeleton, hand, shoulder).is_none());
+        let root = super::BoneId(0);
+        assert!(super::solve_two_bone(skeleton, &mut pose.clone(), root, &target, &knee).is_none());
+
+        let rest = Pose::rest(skeleton);
+        let a = position(&rest, "Human_Armature_LeftArm");
+        let c = position(&rest, "Human_Armature_LeftHand");
+        let target = a + (c - a) * 0.7;
+        let tolerance = 1e-3 * (c - a).norm();
+
+        let mut pose = rest.clone();
+        assert!(chain.solve_ccd(skeleton, &mut pose, &target, 20, tolerance));
+        let mut pose = rest;
+        assert!(chain.solve_fabrik(skeleton, &mut pose, &target, 20, tolerance));
+    }
+
+    #[test]
+    fn head_tracking() {
+        use super::{Constraint, ConstraintKind};
+        use na::{Point3, Vector3};
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+
+        let target = Point3::new(0.0, 10.0, 2.0);
+        let track = Constraint::new(
+            skeleton,
+            "Human_Armature_Head",
+            ConstraintKind::DampedTrack {
+                axis: Vector3::y_axis(),
+                target,
+            },
+        )
+        .unwrap();
+        let copy = Constraint::new(
+            skeleton,
+            "Human_Armature_Neck",
+            ConstraintKind::CopyRotation { source: track.bone },
+        )
+        .unwrap();
+        assert!(Constraint::new(
+            skeleton,
+            "Head",
+            ConstraintKind::CopyRotation { source: track.bone }
+        )
+        .is_err());
+
+        let mut pose = anims.query_pose("walk", 0.5).unwrap();
+        super::apply_constraints(skeleton, &mut pose, &[copy, track]);
+        let head = pose.model_transforms(skeleton)[track.bone.0];
+        let position = Point3::new(head[(0, 3)], head[(1, 3)], head[(2, 3)]);
+        let axis = Vector3::new(head[(0, 1)], head[(1, 1)], head[(2, 1)]).normalize();
+        assert!((axis - (target - position).normalize()).norm() < 1e-3);
+
+        let mut transforms = vec![];
+        anims.query("walk", 0.5, &mut transforms).unwrap();
+        let mut constrained = vec![];
+        anims
+            .query_constrained("walk", 0.5, &[track], &mut constrained)
+            .unwrap();
+        assert_eq!(constrained.len(), transforms.len());
+    }
+
+    #[test]
+    fn baked_animation() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+
+        // The matrices computed from the tracks are the baked ones,
+        // on a frame and in between two frames
+        let run = anims.get_animation("run").unwrap();
+        let times = [
+            run.get_times()[5],
+            run.get_times()[5] + 0.4 * run.get_frame_time(),
+        ];
+        let query = |anims: &super::Animations| {
+            times
+                .iter()
+                .map(|&time| {
+                    let mut transforms = vec![];
+                    anims.query("run", time, &mut transforms).unwrap();
+                    transforms
+                })
+                .collect::<Vec<_>>()
+        };
+        let transforms = query(&anims);
+        anims.bake();
+        assert!(anims.get_animation("run").unwrap().is_baked());
+        for (transforms, baked_transforms) in transforms.iter().zip(query(&anims).iter()) {
+            for (m, baked) in transforms.iter().zip(baked_transforms.iter()) {
+                assert!((m - baked).norm() < 1e-5);
+            }
+        }
+
+        let anim = anims.get_animation("run").unwrap();
+        let hips = anims
+            .get_skeleton()
+            .find_bone("Human_Armature_Hips")
+            .unwrap();
+        let track = anim.get_track(hips);
+        assert_eq!(track.get_rotations().len(), anim.get_times().len());
+        assert_eq!(track.get_scales().len(), anim.get_times().len());
+    }
+
+    #[test]
+    fn interpolated_query() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let frame_time = anims.get_animation("walk").unwrap().get_frame_time();
+
+        // The matrices do not jump when crossing a frame
+        let time = 10.0 * frame_time;
+        let (mut before, mut after) = (vec![], vec![]);
+        anims.query("walk", time - 1e-4, &mut before).unwrap();
+        anims.query("walk", time + 1e-4, &mut after).unwrap();
+        for (m0, m1) in before.iter().zip(after.iter()) {
+            assert!((m0 - m1).norm() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn wrap_modes() {
+        use super::WrapMode;
+        use na::Matrix4;
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+        let duration = anims.get_animation("walk").unwrap().get_duration();
+        let query = |anims: &super::Animations, time: f32| {
+            let mut transforms = vec![];
+            anims.query("walk", time, &mut transforms).unwrap();
+            transforms
+        };
+        let assert_eq_matrices = |a: &[Matrix4<f32>], b: &[Matrix4<f32>]| {
+            for (m0, m1) in a.iter().zip(b.iter()) {
+                assert!((m0 - m1).norm() < 1e-3);
+            }
+        };
+
+        let walk = anims.get_animation_mut("walk").unwrap();
+        walk.set_wrap_mode(WrapMode::Loop);
+        assert_eq_matrices(&query(&anims, 0.3), &query(&anims, 0.3 + 2.0 * duration));
+
+        let walk = anims.get_animation_mut("walk").unwrap();
+        walk.set_wrap_mode(WrapMode::PingPong);
+        assert_eq_matrices(
+            &query(&anims, duration - 0.2),
+            &query(&anims, duration + 0.2),
+        );
+
+        let mut transforms = vec![];
+        anims
+            .query_normalized("walk", 0.5, &mut transforms)
+            .unwrap();
+        assert_eq_matrices(&transforms, &query(&anims, 0.5 * duration));
+
+        assert!(matches!(
+            anims.query("unknown", 0.5, &mut transforms),
+            Err(super::Error::AnimationNotFound { .. })
+        ));
+        assert!(anims
+            .query_normalized("unknown", 0.5, &mut transforms)
+            .is_err());
+    }
+
+    #[test]
+    fn crossfade_walk_run() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+
+        let (mut walk, mut run, mut blended) = (vec![], vec![], vec![]);
+        anims.query("walk", 0.2, &mut walk).unwrap();
+        anims.query("run", 0.1, &mut run).unwrap();
+
+        let mut crossfade = super::Crossfade::new("walk", "run", 0.5);
+        crossfade.query(&anims, 0.2, 0.1, &mut blended).unwrap();
+        for (m, b) in walk.iter().zip(blended.iter()) {
+            assert!((m - b).norm() < 1e-3);
+        }
+
+        crossfade.advance(0.25);
+        assert!((crossfade.get_weight() - 0.5).abs() < 1e-6);
+        crossfade.advance(1.0);
+        assert!(crossfade.is_finished());
+        crossfade.query(&anims, 0.2, 0.1, &mut blended).unwrap();
+        for (m, b) in run.iter().zip(blended.iter()) {
+            assert!((m - b).norm() < 1e-3);
+        }
+
+        assert!(anims
+            .blend("walk", 0.2, "fly", 0.1, 0.5, &mut blended)
+            .is_err());
+    }
+
+    #[test]
+    fn additive_clip() {
+        use super::AdditiveReference;
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+        let walk = anims.query_pose("walk", 0.4).unwrap();
+        let run = anims.query_pose("run", 0.3).unwrap();
+        let first_run = anims.query_pose("run", 0.0).unwrap();
+
+        anims
+            .make_additive("run", AdditiveReference::FirstFrame)
+            .unwrap();
+        assert!(anims.get_animation("run").unwrap().is_additive());
+        let mut transforms = vec![];
+        assert!(matches!(
+            anims.query("run", 0.3, &mut transforms),
+            Err(super::Error::AdditiveAnimation { .. })
+        ));
+        assert!(matches!(
+            anims.add(&mut walk.clone(), "walk", 0.3, 1.0),
+            Err(super::Error::NotAdditiveAnimation { .. })
+        ));
+
+        // The first frame of an additive clip changes nothing
+        let mut pose = walk.clone();
+        anims.add(&mut pose, "run", 0.0, 1.0).unwrap();
+        for (a, b) in pose
+            .model_transforms(anims.get_skeleton())
+            .iter()
+            .zip(walk.model_transforms(anims.get_skeleton()).iter())
+        {
+            assert!((a - b).norm() < 1e-3);
+        }
+
+        // Adding the clip on top of its reference gives back the clip
+        let mut pose = first_run.clone();
+        anims.add(&mut pose, "run", 0.3, 1.0).unwrap();
+        for (a, b) in pose
+            .model_transforms(anims.get_skeleton())
+            .iter()
+            .zip(run.model_transforms(anims.get_skeleton()).iter())
+        {
+            assert!((a - b).norm() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn upper_body_layer() {
+        use super::BoneMask;
+        use na::Matrix4;
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let skeleton = anims.get_skeleton();
+        let upper_body = BoneMask::from_subtree(skeleton, "Human_Armature_Spine").unwrap();
+        assert!(BoneMask::from_subtree(skeleton, "Tail").is_err());
+
+        let spine = skeleton.find_bone("Human_Armature_Spine").unwrap();
+        let hand = skeleton.find_bone("Human_Armature_LeftHand").unwrap();
+        let foot = skeleton.find_bone("Human_Armature_LeftFoot").unwrap();
+        assert_eq!(upper_body.get_weight(hand), 1.0);
+        assert_eq!(upper_body.get_weight(foot), 0.0);
+        assert_eq!(upper_body.inverted().get_weight(foot), 1.0);
+
+        let run = anims.query_pose("run", 0.3).unwrap();
+        let walk = anims.query_pose("walk", 0.6).unwrap();
+        let mut pose = run.clone();
+        anims
+            .layer(&mut pose, "walk", 0.6, 1.0, &upper_body)
+            .unwrap();
+
+        // The legs keep running while the upper body walks
+        for (id, expected) in [(spine, &walk), (hand, &walk), (foot, &run)].iter() {
+            let a: Matrix4<f32> = pose.get_local_transform(*id).clone().into();
+            let b: Matrix4<f32> = expected.get_local_transform(*id).clone().into();
+            assert!((a - b).norm() < 1e-3);
+        }
+
+        // A mask of another skeleton is rejected
+        let spider = super::load(&"./test/spider", 30.0).unwrap();
+        let spider_mask = BoneMask::full(spider.animations.unwrap().get_skeleton());
+        assert!(matches!(
+            anims.layer(&mut pose, "walk", 0.6, 1.0, &spider_mask),
+            Err(super::Error::MaskNotMatching)
+        ));
+        assert!(pose.add_masked(&walk, 1.0, &spider_mask).is_err());
+    }
+
+    #[test]
+    fn locomotion_blend_spaces() {
+        use super::{BlendSpace1D, BlendSpace2D};
+        use na::Point2;
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let walk_duration = anims.get_animation("walk").unwrap().get_duration();
+        let run_duration = anims.get_animation("run").unwrap().get_duration();
+
+        let mut speed = BlendSpace1D::new();
+        speed.add_clip("run", 4.0);
+        speed.add_clip("walk", 1.0);
+        assert_eq!(speed.get_weights(0.0), vec![("walk", 1.0)]);
+        let duration = speed.get_duration(&anims, 2.5).unwrap();
+        assert!((duration - 0.5 * (walk_duration + run_duration)).abs() < 1e-5);
+
+        // The clips are sampled at the same normalized time
+        let (mut walk, mut blended) = (vec![], vec![]);
+        anims.query("walk", 0.5 * walk_duration, &mut walk).unwrap();
+        speed.query(&anims, 1.0, 0.5, &mut blended).unwrap();
+        for (a, b) in walk.iter().zip(blended.iter()) {
+            assert!((a - b).norm() < 1e-3);
+        }
+
+        let mut direction = BlendSpace2D::new();
+        direction.add_clip("walk", Point2::new(0.0, 1.0));
+        direction.add_clip("run", Point2::new(0.0, 2.0));
+        direction.add_clip("death", Point2::new(1.0, 1.0));
+        assert_eq!(direction.get_triangles().len(), 1);
+        let weights = direction.get_weights(&Point2::new(0.25, 1.25));
+        let total: f32 = weights.iter().map(|(_, w)| w).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        assert_eq!(
+            direction.get_weights(&Point2::new(0.0, 5.0)),
+            vec![("run", 1.0)]
+        );
+
+        let pose = direction
+            .query_pose(&anims, &Point2::new(0.25, 1.25), 0.3)
+            .unwrap();
+        let skeleton = anims.get_skeleton();
+        assert_eq!(
+            pose.skinning_transforms(skeleton).len(),
+            skeleton.get_num_vertices_attached_bones()
+        );
+        assert!(BlendSpace1D::new().query_pose(&anims, 1.0, 0.0).is_err());
+
+        // A non-finite parameter has no weights
+        let nan = Point2::new(f32::NAN, 1.0);
+        assert!(direction.get_weights(&nan).is_empty());
+        assert!(direction.query_pose(&anims, &nan, 0.3).is_err());
+
+        // The corners of a square are cocircular, a single diagonal is kept
+        direction.add_clip("walk", Point2::new(1.0, 2.0));
+        assert_eq!(direction.get_triangles().len(), 2);
+        let weights = direction.get_weights(&Point2::new(0.5, 1.5));
+        let total: f32 = weights.iter().map(|(_, w)| w).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn walk_run_state_machine() {
+        use super::{Condition, Motion, Parameter, State, StateMachine, Transition};
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let anims = model.animations.unwrap();
+        let run_duration = anims.get_animation("run").unwrap().get_duration();
+
+        let mut machine = StateMachine::new(State::new("walk", Motion::Clip("walk".to_owned())));
+        machine.add_state(State::new("run", Motion::Clip("run".to_owned())));
+        let mut start_running = Transition::new(Some("walk"), "run", 0.2);
+        start_running.conditions.push(Condition::Greater {
+            parameter: "speed".to_owned(),
+            value: 2.0,
+        });
+        machine.add_transition(start_running).unwrap();
+        let mut stop = Transition::new(Some("run"), "walk", 0.0);
+        stop.conditions
+            .push(Condition::Triggered("stop".to_owned()));
+        stop.exit_time = Some(1.0);
+        machine.add_transition(stop).unwrap();
+        assert!(machine
+            .add_transition(Transition::new(None, "fly", 0.1))
+            .is_err());
+
+        let (mut transforms, mut expected) = (vec![], vec![]);
+        machine.update(&anims, 0.1, &mut transforms).unwrap();
+        anims.query("walk", 0.1, &mut expected).unwrap();
+        for (a, b) in transforms.iter().zip(expected.iter()) {
+            assert!((a - b).norm() < 1e-3);
+        }
+
+        // Crossfade towards the run
+        machine.set_float("speed", 3.0);
+        machine.update(&anims, 0.1, &mut transforms).unwrap();
+        assert_eq!(machine.get_current_state(), "run");
+        assert!(machine.is_in_transition());
+        machine.update(&anims, 0.2, &mut transforms).unwrap();
+        assert!(!machine.is_in_transition());
+        anims.query("run", 0.2, &mut expected).unwrap();
+        for (a, b) in transforms.iter().zip(expected.iter()) {
+            assert!((a - b).norm() < 1e-3);
+        }
+
+        // The run finishes its cycle before stopping
+        machine.set_trigger("stop");
+        machine.update(&anims, 0.1, &mut transforms).unwrap();
+        assert_eq!(machine.get_current_state(), "run");
+        machine
+            .update(&anims, run_duration, &mut transforms)
+            .unwrap();
+        assert_eq!(machine.get_current_state(), "walk");
+        assert_eq!(
+            machine.get_parameter("stop"),
+            Some(Parameter::Trigger(false))
+        );
+
+        // The exit time is checked at the same point of every cycle, a trigger
+        // set after the exit time of the first cycle waits for the next one
+        let walk_duration = anims.get_animation("walk").unwrap().get_duration();
+        let mut wave = Transition::new(Some("walk"), "run", 0.0);
+        wave.conditions
+            .push(Condition::Triggered("wave".to_owned()));
+        wave.exit_time = Some(0.5);
+        machine.add_transition(wave).unwrap();
+        machine.set_float("speed", 0.0);
+        machine
+            .update(&anims, 1.6 * walk_duration, &mut transforms)
+            .unwrap();
+        // The clip of a state loops instead of freezing after its first cycle
+        let normalized_time = machine.get_normalized_time();
+        assert!(normalized_time > 1.0);
+        anims
+            .query(
+                "walk",
+                normalized_time.fract() * walk_duration,
+                &mut expected,
+            )
+            .unwrap();
+        for (a, b) in transforms.iter().zip(expected.iter()) {
+            assert!((a - b).norm() < 1e-3);
+        }
+        machine.set_trigger("wave");
+        machine
+            .update(&anims, 0.1 * walk_duration, &mut transforms)
+            .unwrap();
+        assert_eq!(machine.get_current_state(), "walk");
+        machine
+            .update(&anims, 0.85 * walk_duration, &mut transforms)
+            .unwrap();
+        assert_eq!(machine.get_current_state(), "run");
+
+        let encoded = bincode::serialize(&machine).unwrap();
+        let decoded: StateMachine = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, machine);
+    }
+
+    #[test]
+    fn footstep_events() {
+        use super::WrapMode;
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+        let duration = anims.get_animation("walk").unwrap().get_duration();
+        anims
+            .add_event("walk", "footstep_right", 0.6 * duration)
+            .unwrap();
+        anims
+            .add_event("walk", "footstep_left", 0.1 * duration)
+            .unwrap();
+        assert!(anims.add_event("fly", "flap", 0.0).is_err());
+        for time in [-0.1, 2.0 * duration, f32::NAN].iter() {
+            assert!(matches!(
+                anims.add_event("walk", "footstep", *time),
+                Err(super::Error::InvalidEventTime { .. })
+            ));
+        }
+
+        let names = |anims: &super::Animations, previous_time: f32, time: f32| {
+            anims
+                .events_between("walk", previous_time, time)
+                .unwrap()
+                .iter()
+                .map(|event| event.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&anims, 0.0, 0.5 * duration), vec!["footstep_left"]);
+        assert!(names(&anims, 0.2 * duration, 0.5 * duration).is_empty());
+        // Clamped at the end of the clip
+        assert_eq!(
+            names(&anims, 0.5 * duration, 3.0 * duration),
+            vec!["footstep_right"]
+        );
+
+        // Both footsteps of each crossed cycle
+        let walk = anims.get_animation_mut("walk").unwrap();
+        walk.set_wrap_mode(WrapMode::Loop);
+        assert_eq!(
+            names(&anims, 0.5 * duration, 1.2 * duration),
+            vec!["footstep_right", "footstep_left"]
+        );
+        assert_eq!(names(&anims, 0.0, 2.0 * duration).len(), 4);
+
+        // An event at the end of a looping clip is reported at the start of the next cycle
+        anims.add_event("walk", "turn", duration).unwrap();
+        assert!(names(&anims, 0.0, 0.05 * duration).is_empty());
+        assert_eq!(names(&anims, 0.9 * duration, 1.05 * duration), vec!["turn"]);
+        assert!(names(&anims, 0.9 * duration, duration).is_empty());
+        assert_eq!(names(&anims, duration, 1.05 * duration), vec!["turn"]);
+        assert_eq!(
+            names(&anims, 0.5 * duration, 2.5 * duration),
+            vec![
+                "footstep_right",
+                "turn",
+                "footstep_left",
+                "footstep_right",
+                "turn",
+                "footstep_left"
+            ]
+        );
+    }
+
+    #[test]
+    fn read_footstep_events() {
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+        anims.read_events("./test/events/walk.json").unwrap();
+        let events = anims.get_animation("walk").unwrap().get_events();
+        let names = events
+            .iter()
+            .map(|event| event.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["footstep_left", "footstep_right"]);
+        assert!((events[0].time - 0.1).abs() < 1e-6);
+
+        assert!(matches!(
+            anims.read_events("./test/events/malformed.json"),
+            Err(super::Error::Json(_))
+        ));
+        assert!(matches!(
+            anims.read_events("./test/events/missing.json"),
+            Err(super::Error::IoError(_))
+        ));
+        assert_eq!(anims.get_animation("walk").unwrap().get_events().len(), 2);
+    }
+
+    #[test]
+    fn walk_root_motion() {
+        use super::{AdditiveReference, WrapMode};
+        use na::{Matrix4, Vector3};
+
+        let model = super::load(&"./test/human", 30.0).unwrap();
+        let mut anims = model.animations.unwrap();
+        let hips = anims
+            .get_skeleton()
+            .find_bone("Human_Armature_Hips")
+            .unwrap();
+        let before = anims
+            .query_bone_transform("walk", 0.4, "Human_Armature_Hips")
+            .unwrap();
+        let start = anims
+            .query_bone_transform("walk", 0.0, "Human_Armature_Hips")
+            .unwrap();
+        anims
+            .make_additive("run", AdditiveReference::FirstFrame)
+            .unwrap();
+        let additive_translations = anims
+            .get_animation("run")
+            .unwrap()
+            .get_track(hips)
+            .get_translations()
+            .to_vec();
+
+        let up = Vector3::z_axis();
+        assert!(anims.extract_root_motion("Tail", &up).is_err());
+        anims
+            .extract_root_motion("Human_Armature_Hips", &up)
+            .unwrap();
+        assert!(matches!(
+            anims.extract_root_motion("Human_Armature_Hips", &up),
+            Err(super::Error::RootMotionAlreadyExtracted)
+        ));
+        let walk = anims.get_animation("walk").unwrap();
+        assert!(walk.get_root_motion().is_some());
+
+        // The additive clips are left unchanged
+        let run = anims.get_animation("run").unwrap();
+        assert!(run.get_root_motion().is_none());
+        assert_eq!(
+            run.get_track(hips).get_translations(),
+            &additive_translations[..]
+        );
+
+        // The hips stay in place horizontally
+        let after = walk.query_bone_transform(anims.get_skeleton(), 0.4, hips);
+        assert!((after[(0, 3)] - start[(0, 3)]).abs() < 1e-3);
+        assert!((after[(1, 3)] - start[(1, 3)]).abs() < 1e-3);
+
+        // The root motion moves them back to where they were
+        let root_motion: Matrix4<f32> = anims
+            .query_root_motion("walk", 0.0, 0.4)
+            .unwrap()
+            .to_homogeneous();
+        assert!((root_motion * after - before).norm() < 1e-3);
+
+        // The motion accumulates over the cycles of a looping clip
+        let walk = anims.get_animation_mut("walk").unwrap();
+        walk.set_wrap_mode(WrapMode::Loop);
+        let duration = walk.get_duration();
+        let cycle = walk.query_root_motion(0.0, duration);
+        let two_cycles = walk.query_root_motion(0.0, 2.0 * duration);
+        assert!(((cycle * cycle).to_homogeneous() - two_cycles.to_homogeneous()).norm() < 1e-3);
+    }
+
+    #[test]
+    fn prune_spider_bones() {
+        let mut model = super::load(&"./test/spider", 30.0).unwrap();
+        let mut transforms = vec![];
+        let skinned = {
+            let anims = model.animations.as_ref().unwrap();
+            anims.query("walk", 0.5, &mut transforms).unwrap();
+            model.vertices.skin(&transforms)
+        };
+        // The name of each bone and of its parent
+        let parent_names = |skeleton: &super::Skeleton| {
+            let names = skeleton.get_joint_names();
+            skeleton
+                .get_bones()
+                .iter()
+                .zip(names.iter())
+                .map(|(bone, name)| {
+                    (
+                        name.clone(),
+                        bone.get_parent_idx().map(|p| names[p].clone()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let old_parents = parent_names(model.animations.as_ref().unwrap().get_skeleton());
+
+        let remap = super::prune_bones(&mut model);
+        let anims = model.animations.as_ref().unwrap();
+        let skeleton = anims.get_skeleton();
+        assert_eq!(
+            skeleton.get_bones().len() + remap.get_removed_bones().len(),
+            old_parents.len()
+        );
+        for name in remap.get_removed_bones() {
+            assert!(skeleton.find_bone(name).is_none());
+        }
+        // Only whole subtrees are removed, the kept bones keep their parent
+        for parent in parent_names(skeleton) {
+            assert!(old_parents.contains(&parent));
+        }
+        assert_eq!(remap.get_bone(super::BoneId(old_parents.len())), None);
+
+        // The pruned model is posed the same way
+        anims.query("walk", 0.5, &mut transforms).unwrap();
+        let (positions, normals) = model.vertices.skin(&transforms);
+        for (p, pruned) in skinned.0.iter().zip(positions.iter()) {
+            assert!((p - pruned).norm() < 1e-3);
+        }
+        for (n, pruned) in skinned.1.iter().zip(normals.iter()) {
+            assert!((n - pruned).norm() < 1e-3);
+        }
+    }
 }
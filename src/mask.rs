@@ -0,0 +1,69 @@
+use super::skeleton::{BoneId, Skeleton};
+use super::Error;
+
+use serde::{Deserialize, Serialize};
+
+/// A weight for each bone of a skeleton, indexed by joint index
+///
+/// A mask restricts the blending and layering operations to a part of the
+/// skeleton, e.g. the upper body plays `aim` while the legs play `run`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoneMask {
+    weights: Vec<f32>,
+}
+
+impl BoneMask {
+    /// A mask with a weight of 0.0 for every bone
+    pub fn empty(skeleton: &Skeleton) -> Self {
+        BoneMask {
+            weights: vec![0.0; skeleton.get_bones().len()],
+        }
+    }
+
+    /// A mask with a weight of 1.0 for every bone
+    pub fn full(skeleton: &Skeleton) -> Self {
+        BoneMask {
+            weights: vec![1.0; skeleton.get_bones().len()],
+        }
+    }
+
+    /// A mask with a weight of 1.0 for a bone and all the bones below it
+    pub fn from_subtree(skeleton: &Skeleton, bone_name: &str) -> Result<Self, Error> {
+        let id = skeleton
+            .find_bone(bone_name)
+            .ok_or_else(|| Error::BoneNotFound {
+                name: bone_name.to_owned(),
+            })?;
+
+        let mut mask = BoneMask::empty(skeleton);
+        mask.set_subtree_weight(skeleton, id, 1.0);
+
+        Ok(mask)
+    }
+
+    /// Set the weight of a bone and all the bones below it
+    pub fn set_subtree_weight(&mut self, skeleton: &Skeleton, id: BoneId, weight: f32) {
+        for id in skeleton.descendants(id) {
+            self.weights[id.0] = weight;
+        }
+    }
+
+    pub fn set_weight(&mut self, id: BoneId, weight: f32) {
+        self.weights[id.0] = weight;
+    }
+
+    pub fn get_weight(&self, id: BoneId) -> f32 {
+        self.weights[id.0]
+    }
+
+    pub fn get_weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    /// The complementary mask, e.g. the lower body from the upper body
+    pub fn inverted(&self) -> Self {
+        BoneMask {
+            weights: self.weights.iter().map(|w| 1.0 - w).collect(),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use super::{Data, Error, Vertices};
+
+use na::{Point2, Point3, Vector3};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+fn write_mesh<P: AsRef<Path>>(
+    positions: &[Point3<f32>],
+    normals: &[Vector3<f32>],
+    texcoords: &[Point2<f32>],
+    indices: &[u32],
+    path: P,
+) -> Result<(), Error> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "# Exported by rib")?;
+
+    for p in positions {
+        writeln!(file, "v {} {} {}", p.x, p.y, p.z)?;
+    }
+    for t in texcoords {
+        writeln!(file, "vt {} {}", t.x, t.y)?;
+    }
+    for n in normals {
+        writeln!(file, "vn {} {} {}", n.x, n.y, n.z)?;
+    }
+
+    // Positions, texcoords and normals share the same indices.
+    // OBJ indices start from 1
+    for triangle in indices.chunks(3) {
+        write!(file, "f")?;
+        for idx in triangle {
+            let idx = idx + 1;
+            write!(file, " {}/{}/{}", idx, idx, idx)?;
+        }
+        writeln!(file)?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Write the vertices in their bind pose to a Wavefront OBJ file
+pub fn write_obj<P: AsRef<Path>>(vertices: &Vertices, path: P) -> Result<(), Error> {
+    write_mesh(
+        &vertices.positions,
+        &vertices.normals,
+        &vertices.texcoords,
+        &vertices.indices,
+        path,
+    )
+}
+
+/// Skin the vertices on the CPU at a specific time of an animation
+/// and write the resulting mesh to a Wavefront OBJ file
+pub fn write_posed_obj<P: AsRef<Path>>(
+    data: &Data,
+    name: &str,
+    time: f32,
+    path: P,
+) -> Result<(), Error> {
+    let anims = data
+        .animations
+        .as_ref()
+        .ok_or_else(|| Error::AnimationNotFound {
+            name: name.to_owned(),
+        })?;
+    let anim = anims.get_clip_or_err(name)?;
+
+    let vertices = &data.vertices;
+    let mut transforms = vec![];
+    anim.query(anims.get_skeleton(), time, &mut transforms);
+    let (positions, normals) = vertices.skin(&transforms);
+    write_mesh(
+        &positions,
+        &normals,
+        &vertices.texcoords,
+        &vertices.indices,
+        path,
+    )
+}
+
+/// Write a numbered sequence of posed OBJ files
+///
+/// The animation is sampled every `frame_time` from `start` to `end` (inclusive).
+/// The files are named after `path` suffixed by the frame number,
+/// e.g. `walk.obj` gives `walk_0000.obj`, `walk_0001.obj`, ...
+pub fn write_posed_obj_sequence<P: AsRef<Path>>(
+    data: &Data,
+    name: &str,
+    start: f32,
+    end: f32,
+    frame_time: f32,
+    path: P,
+) -> Result<(), Error> {
+    if frame_time.is_nan() || frame_time <= 0.0 {
+        return Err(Error::InvalidFrameTime { frame_time });
+    }
+
+    let path = path.as_ref();
+    let stem = path
+        .file_stem()
+        .ok_or_else(|| Error::MissingFileStem {
+            path: path.to_string_lossy().into_owned(),
+        })?
+        .to_string_lossy();
+
+    let num_frames = ((end - start) / frame_time).floor() as usize + 1;
+    for frame in 0..num_frames {
+        let time = start + (frame as f32) * frame_time;
+        let filename = path.with_file_name(format!("{}_{:04}.obj", stem, frame));
+
+        write_posed_obj(data, name, time, filename)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use super::animation::{compute_final_transforms, compute_global_transforms};
+use super::mask::BoneMask;
+use super::skeleton::{BoneId, Skeleton};
+use super::transform::Transform;
+use super::Error;
+
+use na::{Isometry3, Matrix4};
+
+/// The local transforms of the joints of a skeleton, indexed by joint index
+///
+/// A pose is sampled from an animation with `Animation::query_pose`, then can be
+/// edited (e.g. by the IK solvers) before computing its skinning matrices
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pose {
+    local_transforms: Vec<Transform>,
+}
+
+impl Pose {
+    pub fn new(local_transforms: Vec<Transform>) -> Self {
+        Pose { local_transforms }
+    }
+
+    /// The rest pose of a skeleton
+    pub fn rest(skeleton: &Skeleton) -> Self {
+        let local_transforms = skeleton
+            .get_rest_local_transforms()
+            .iter()
+            .map(|m| m.into())
+            .collect();
+
+        Pose { local_transforms }
+    }
+
+    pub fn get_local_transforms(&self) -> &[Transform] {
+        &self.local_transforms
+    }
+
+    pub fn get_local_transform(&self, id: BoneId) -> &Transform {
+        &self.local_transforms[id.0]
+    }
+
+    pub fn set_local_transform(&mut self, id: BoneId, local_transform: Transform) {
+        self.local_transforms[id.0] = local_transform;
+    }
+
+    /// The model space transforms of the joints, indexed by joint index
+    pub fn model_transforms(&self, skeleton: &Skeleton) -> Vec<Matrix4<f32>> {
+        compute_global_transforms(skeleton, &self.local_matrices())
+    }
+
+    /// The skinning matrices of the pose, indexed by skinning index
+    ///
+    /// These are the same matrices as the ones returned by `Animations::query`
+    pub fn skinning_transforms(&self, skeleton: &Skeleton) -> Vec<Matrix4<f32>> {
+        let mut transforms = vec![];
+        self.write_skinning_transforms(skeleton, &mut transforms);
+
+        transforms
+    }
+
+    /// Write the skinning matrices of the pose into a buffer
+    pub fn write_skinning_transforms(
+        &self,
+        skeleton: &Skeleton,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) {
+        compute_final_transforms(
+            skeleton,
+            &self.local_matrices(),
+            &Matrix4::identity(),
+            transforms,
+        );
+    }
+
+    /// Blend the local transforms of two poses
+    ///
+    /// The translations and scales are interpolated linearly and the
+    /// rotations with slerp. A weight of 0.0 gives this pose, 1.0 the other one.
+    pub fn blend(&self, other: &Pose, weight: f32) -> Pose {
+        let local_transforms = self
+            .local_transforms
+            .iter()
+            .zip(other.local_transforms.iter())
+            .map(|(a, b)| a.interpolate(b, weight))
+            .collect();
+
+        Pose { local_transforms }
+    }
+
+    /// Blend the local transforms of two poses, the weight of each bone
+    /// being scaled by the one of the mask
+    ///
+    /// The mask must have been made for the skeleton of the pose
+    pub fn blend_masked(&self, other: &Pose, weight: f32, mask: &BoneMask) -> Result<Pose, Error> {
+        self.check_mask(mask)?;
+
+        let local_transforms = self
+            .local_transforms
+            .iter()
+            .zip(other.local_transforms.iter())
+            .zip(mask.get_weights().iter())
+            .map(|((a, b), bone_weight)| a.interpolate(b, weight * bone_weight))
+            .collect();
+
+        Ok(Pose { local_transforms })
+    }
+
+    /// Add the offsets of an additive pose, scaled by a weight, see `Animation::make_additive`
+    pub fn add(&mut self, additive: &Pose, weight: f32) {
+        for (local_transform, difference) in self
+            .local_transforms
+            .iter_mut()
+            .zip(additive.local_transforms.iter())
+        {
+            *local_transform = local_transform.add(difference, weight);
+        }
+    }
+
+    /// Add the offsets of an additive pose, the weight of each bone
+    /// being scaled by the one of the mask
+    ///
+    /// The mask must have been made for the skeleton of the pose
+    pub fn add_masked(
+        &mut self,
+        additive: &Pose,
+        weight: f32,
+        mask: &BoneMask,
+    ) -> Result<(), Error> {
+        self.check_mask(mask)?;
+
+        for ((local_transform, difference), bone_weight) in self
+            .local_transforms
+            .iter_mut()
+            .zip(additive.local_transforms.iter())
+            .zip(mask.get_weights().iter())
+        {
+            *local_transform = local_transform.add(difference, weight * bone_weight);
+        }
+
+        Ok(())
+    }
+
+    fn check_mask(&self, mask: &BoneMask) -> Result<(), Error> {
+        if mask.get_weights().len() == self.local_transforms.len() {
+            Ok(())
+        } else {
+            Err(Error::MaskNotMatching)
+        }
+    }
+
+    /// The model space transform of one joint
+    pub(crate) fn model_matrix(&self, skeleton: &Skeleton, id: BoneId) -> Matrix4<f32> {
+        std::iter::once(id).chain(skeleton.ancestors(id)).fold(
+            Matrix4::identity(),
+            |transform, id| {
+                let local_transform: Matrix4<f32> = self.local_transforms[id.0].clone().into();
+                local_transform * transform
+            },
+        )
+    }
+
+    /// The model space position and rotation of one joint, without its scale
+    pub(crate) fn model_isometry(&self, skeleton: &Skeleton, id: BoneId) -> Isometry3<f32> {
+        let transform: Transform = (&self.model_matrix(skeleton, id)).into();
+        (&transform).into()
+    }
+
+    fn local_matrices(&self) -> Vec<Matrix4<f32>> {
+        self.local_transforms
+            .iter()
+            .map(|t| t.clone().into())
+            .collect()
+    }
+}
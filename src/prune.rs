@@ -0,0 +1,124 @@
+use super::skeleton::BoneId;
+use super::Data;
+
+/// Correspondence between the bones before and after `prune_bones`
+///
+/// The names of the kept bones do not change so gameplay code
+/// can still find them with `Skeleton::find_bone`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneRemap {
+    // The new index of each old bone
+    bones: Vec<Option<usize>>,
+    // The new skinning index of each old skinning index
+    skinning_indices: Vec<Option<usize>>,
+    removed_bones: Vec<String>,
+}
+
+impl BoneRemap {
+    /// The new id of a bone, None if it has been removed
+    pub fn get_bone(&self, id: BoneId) -> Option<BoneId> {
+        self.bones.get(id.0).copied().flatten().map(BoneId)
+    }
+
+    /// The new skinning index, None if no vertices were weighted by it
+    pub fn get_skinning_idx(&self, idx_transform: usize) -> Option<usize> {
+        self.skinning_indices.get(idx_transform).copied().flatten()
+    }
+
+    /// The names of the removed bones
+    pub fn get_removed_bones(&self) -> &[String] {
+        &self.removed_bones
+    }
+}
+
+/// Remove the bones that neither weight any vertex nor are animated
+///
+/// A bone is only removed with its whole subtree, e.g. the helper or IK
+/// bones at the end of a chain exported by the modeler, so the hierarchy
+/// of the kept bones does not change. The skinning indices
+/// are compacted and the `bone_ids` of the vertices renumbered accordingly.
+/// Returns the remapping of the bones and of the skinning indices.
+pub fn prune_bones(data: &mut Data) -> BoneRemap {
+    let animations = if let Some(animations) = &mut data.animations {
+        animations
+    } else {
+        return BoneRemap {
+            bones: vec![],
+            skinning_indices: vec![],
+            removed_bones: vec![],
+        };
+    };
+
+    // The skinning indices actually referenced by the vertices
+    let skeleton = animations.get_skeleton();
+    let mut weighted = vec![false; skeleton.get_num_vertices_attached_bones()];
+    if let (Some(bone_ids), Some(weights)) = (&data.vertices.bone_ids, &data.vertices.weights) {
+        for (b, w) in bone_ids.iter().zip(weights.iter()) {
+            for (bone_id, weight) in b.iter().zip(w.iter()) {
+                if *weight > 0.0 {
+                    if let Some(weighted) = weighted.get_mut(*bone_id as usize) {
+                        *weighted = true;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut num_skinning_indices = 0;
+    let skinning_indices = weighted
+        .iter()
+        .map(|&weighted| {
+            if weighted {
+                num_skinning_indices += 1;
+                Some(num_skinning_indices - 1)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut keep = skeleton
+        .get_bones()
+        .iter()
+        .enumerate()
+        .map(|(idx, bone)| {
+            let weighted = bone.idx_transform.map(|idx| weighted[idx]) == Some(true);
+            weighted || animations.is_joint_animated(BoneId(idx))
+        })
+        .collect::<Vec<_>>();
+    // A bone with a kept descendant is kept as well. The parents come
+    // before their children so the children are visited first in reverse
+    for (idx, bone) in skeleton.get_bones().iter().enumerate().rev() {
+        if let (true, Some(parent_idx)) = (keep[idx], bone.get_parent_idx()) {
+            keep[parent_idx] = true;
+        }
+    }
+    let removed_bones = skeleton
+        .get_joint_names()
+        .iter()
+        .zip(keep.iter())
+        .filter(|(_, &keep)| !keep)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let bones = animations.prune(&keep, &skinning_indices);
+
+    // The vertices with a null weight keep the first skinning index
+    if let Some(bone_ids) = &mut data.vertices.bone_ids {
+        for b in bone_ids.iter_mut() {
+            for bone_id in b.iter_mut() {
+                *bone_id = skinning_indices
+                    .get(*bone_id as usize)
+                    .copied()
+                    .flatten()
+                    .unwrap_or(0) as i32;
+            }
+        }
+    }
+
+    BoneRemap {
+        bones,
+        skinning_indices,
+        removed_bones,
+    }
+}
@@ -0,0 +1,132 @@
+use super::animation::Animation;
+use super::skeleton::Skeleton;
+use super::transform::{from_matrix4, Transform};
+use super::Error;
+
+use na::Matrix4;
+use std::collections::HashMap;
+
+/// Length under which a bone is considered degenerated
+/// and its translations are not rescaled
+const MIN_BONE_LENGTH: f32 = 1e-6;
+
+/// Scale under which the scale of a source joint is not transferred
+const MIN_SCALE: f32 = 1e-6;
+
+/// Match the joints of the target skeleton with the joints of the source skeleton
+///
+/// `joint_map` renames the source joints (source name -> target name),
+/// the other joints are matched by name.
+/// Returns for each target joint the index of its source joint if there is one.
+pub(crate) fn match_joints(
+    source: &Skeleton,
+    target: &Skeleton,
+    joint_map: &HashMap<String, String>,
+) -> Result<Vec<Option<usize>>, Error> {
+    let source_names = source.get_joint_names();
+    let target_names = target.get_joint_names();
+
+    for (source_name, target_name) in joint_map.iter() {
+        if !source_names.contains(source_name) {
+            return Err(Error::BoneNotFound {
+                name: source_name.clone(),
+            });
+        }
+        if !target_names.contains(target_name) {
+            return Err(Error::BoneNotFound {
+                name: target_name.clone(),
+            });
+        }
+    }
+
+    let mut matches = vec![None; target_names.len()];
+    for (source_idx, source_name) in source_names.iter().enumerate() {
+        let name = joint_map.get(source_name).unwrap_or(source_name);
+        if let Some(target_idx) = target_names.iter().position(|n| n == name) {
+            matches[target_idx] = Some(source_idx);
+        }
+    }
+
+    Ok(matches)
+}
+
+/// Retarget an animation of the source skeleton onto the target skeleton
+///
+/// The rotations of the source joints relative to their rest pose are
+/// applied on top of the rest rotations of the matched target joints.
+/// The offsets of the translations from the rest pose are rescaled by the ratio
+/// between the target and the source bone lengths. The target joints without
+/// any match keep their rest transform.
+pub(crate) fn retarget_animation(
+    source: &Skeleton,
+    anim: &Animation,
+    target: &Skeleton,
+    matches: &[Option<usize>],
+) -> Vec<collada::Animation> {
+    let to_transforms =
+        |m: Vec<Matrix4<f32>>| m.iter().map(|m| m.into()).collect::<Vec<Transform>>();
+    let source_rests = to_transforms(source.get_rest_local_transforms());
+    let target_rests = to_transforms(target.get_rest_local_transforms());
+
+    let sample_times = anim
+        .get_sampled_local_transforms()
+        .map(|(time, _)| time)
+        .collect::<Vec<_>>();
+
+    target
+        .get_joint_names()
+        .iter()
+        .enumerate()
+        .map(|(target_idx, name)| {
+            let target_rest = &target_rests[target_idx];
+
+            let sample_poses = anim
+                .get_sampled_local_transforms()
+                .map(|(_, locals)| {
+                    let local = if let Some(source_idx) = matches[target_idx] {
+                        let source_rest = &source_rests[source_idx];
+                        let source_local = &locals[source_idx];
+
+                        let delta_rotation =
+                            source_rest.get_rotation().inverse() * source_local.get_rotation();
+                        let rotation = target_rest.get_rotation() * delta_rotation;
+
+                        let source_length = source_rest.get_translation().norm();
+                        let ratio = if source_length > MIN_BONE_LENGTH {
+                            target_rest.get_translation().norm() / source_length
+                        } else {
+                            1.0
+                        };
+                        let delta_translation =
+                            source_local.get_translation() - source_rest.get_translation();
+                        let translation = target_rest.get_translation() + delta_translation * ratio;
+
+                        let delta_scale = source_local.get_scale().zip_map(
+                            source_rest.get_scale(),
+                            |local, rest| {
+                                if rest.abs() > MIN_SCALE {
+                                    local / rest
+                                } else {
+                                    1.0
+                                }
+                            },
+                        );
+                        let scale = target_rest.get_scale().component_mul(&delta_scale);
+
+                        Transform::from_parts(translation, rotation, scale)
+                    } else {
+                        target_rest.clone()
+                    };
+
+                    from_matrix4(&local.into())
+                })
+                .collect();
+
+            collada::Animation {
+                target: format!("{}/transform", name),
+                sample_times: sample_times.clone(),
+                sample_poses,
+            }
+        })
+        .collect()
+}
@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Skeleton {
-    root: Option<Bone>,
+    // The bones, stored parents before their children.
+    // The index of a bone is the index of its name
+    bones: Vec<Bone>,
     joint_names: Vec<String>,
 }
 impl Default for Skeleton {
@@ -11,119 +13,179 @@ impl Default for Skeleton {
 }
 
 use super::transform::to_matrix4;
+use super::Error;
 impl Skeleton {
     pub fn new() -> Self {
         Skeleton {
-            root: None,
+            bones: vec![],
             joint_names: vec![],
         }
     }
 
-    /// Parse the first skeleton
-    pub fn from(doc: &collada::document::ColladaDocument) -> Option<Skeleton> {
-        if let Some(skeletons) = &doc.get_skeletons() {
-            if let Some(skeleton) = skeletons.first() {
-                if let Some(bind_data_set) = &doc.get_bind_data_set() {
-                    let bind_data = &bind_data_set.bind_data[0];
-                    let mut s = Skeleton::new();
-
-                    let mut prev_inv_bind_pose = Matrix4::identity().into();
-                    for (joint_idx, joint) in skeleton.joints.iter().enumerate() {
-                        let parent_idx = if joint.parent_index == 255 {
-                            // Root case
-                            None
-                        } else {
-                            Some(joint.parent_index as usize)
-                        };
-                        let bind_data_joint_idx =
-                            bind_data.joint_names.iter().position(|joint_name| {
-                                let skeleton_name =
-                                    bind_data.skeleton_name.as_ref().unwrap().replace(" ", "_");
-                                let bind_data_name = format!("{}_{}", skeleton_name, joint_name);
-                                bind_data_name == joint.name
-                            });
-                        let mut vertices_attached = false;
-                        let mut idx_transform = None;
-                        let inverse_bind_pose =
-                            if let Some(bind_data_joint_idx) = bind_data_joint_idx {
-                                let inverse_bind_pose =
-                                    bind_data.inverse_bind_poses[bind_data_joint_idx];
-                                prev_inv_bind_pose = inverse_bind_pose;
-                                vertices_attached = true;
-                                idx_transform = Some(bind_data_joint_idx);
-
-                                inverse_bind_pose
-                            } else {
-                                prev_inv_bind_pose
-                            };
-
-                        let bone = Bone::new(
-                            joint_idx,
-                            parent_idx,
-                            to_matrix4(&inverse_bind_pose),
-                            vertices_attached,
-                            idx_transform,
-                        );
-                        let name = joint.name.to_string();
-                        s.add(name, bone);
-                    }
+    /// Parse the skeletons of the document
+    ///
+    /// The joints of all the skeletons are gathered into one forest, e.g. for
+    /// rigs having an IK-target hierarchy or a prop root beside the main chain.
+    /// The joints of the skin are resolved to the joint nodes through their ids
+    /// or their sids, an error is returned if one of them cannot be found.
+    pub fn from(doc: &collada::document::ColladaDocument) -> Result<Option<Skeleton>, Error> {
+        let skeletons = if let Some(skeletons) = doc.get_skeletons() {
+            skeletons
+        } else {
+            return Ok(None);
+        };
+        let bind_data_set = if let Some(bind_data_set) = doc.get_bind_data_set() {
+            bind_data_set
+        } else {
+            return Ok(None);
+        };
+        let bind_data = &bind_data_set.bind_data[0];
+        if skeletons.is_empty() {
+            return Ok(None);
+        }
 
-                    Some(s)
-                } else {
-                    None
-                }
+        // The parent indices of the COLLADA joints are stored on 8 bits
+        // so the hierarchy is retrieved from the joint nodes when possible
+        let skeleton_roots = skeleton_roots(&doc.root_element);
+        let mut nodes = vec![];
+        joint_nodes(
+            &doc.root_element,
+            None,
+            skeleton_roots.is_empty(),
+            &skeleton_roots,
+            &mut nodes,
+        );
+        let node_parents = nodes
+            .iter()
+            .map(|node| (node.id.clone(), node.parent.clone()))
+            .collect::<HashMap<_, _>>();
 
-            /*if let Some(parent_name) = &parent_name {
-                // The bone has a parent
-                let bone = Bone::new(name, Some(parent_name.clone()), to_matrix4(&inverse_bind_pose));
-                // keep trace of the parent bone hierarchy
-                let mut bones_stack = vec![bone];
-                let mut cur_parent =  &s.joints[j.parent_index as usize];
-                while !skeleton.contains(&cur_parent.name) {
-                    let parent_index = cur_parent.parent_index as usize;
-                    let parent_name = if parent_index == 255 {
-                        None
-                    } else {
-                        let parent_j = &s.joints[parent_index];
-                        Some(parent_j.name.clone())
-                    };
-                    let inverse_bind_pose_parent = to_matrix4(&bind_data.inverse_bind_poses[parent_index]);
-                    let cur_bone = Bone::new(cur_parent.name.clone(), parent_name, inverse_bind_pose_parent);
-                    bones_stack.push(cur_bone);
-
-                    cur_parent = &s.joints[parent_index];
+        // (name, parent name, rest local transform) of the joints
+        let mut joints = vec![];
+        let mut joint_indices = HashMap::new();
+        for skeleton in &skeletons {
+            for (joint_idx, joint) in skeleton.joints.iter().enumerate() {
+                if joint_indices.contains_key(&joint.name) {
+                    // The joint is shared by several skeletons
+                    continue;
                 }
 
-                // cur_parent is in the bone
-                // add the parents_bone successively
-                while !bones_stack.is_empty() {
-                    let bone = bones_stack.pop().unwrap();
-                    skeleton.add(bone);
-                }
-            } else {*/
+                let parent_name = if let Some(parent_name) = node_parents.get(&joint.name) {
+                    parent_name.clone()
+                } else if joint.parent_index == 255 {
+                    // Root case
+                    None
+                } else {
+                    Some(skeleton.joints[joint.parent_index as usize].name.clone())
+                };
+                // The local transform of the joint node
+                let rest_local_transform = skeleton
+                    .bind_poses
+                    .get(joint_idx)
+                    .map(to_matrix4)
+                    .unwrap_or_else(Matrix4::identity);
+
+                joint_indices.insert(joint.name.clone(), joints.len());
+                joints.push((joint.name.clone(), parent_name, rest_local_transform));
+            }
+        }
+
+        // Sort the joints so that the parents come before their children
+        let parents = joints
+            .iter()
+            .map(|(_, parent_name, _)| {
+                parent_name
+                    .as_ref()
+                    .and_then(|parent_name| joint_indices.get(parent_name).copied())
+            })
+            .collect::<Vec<_>>();
+        let mut children = vec![vec![]; joints.len()];
+        for (idx, parent) in parents.iter().enumerate() {
+            if let Some(parent) = parent {
+                children[*parent].push(idx);
+            }
+        }
+        let mut order = Vec::with_capacity(joints.len());
+        let mut stack = (0..joints.len())
+            .filter(|idx| parents[*idx].is_none())
+            .rev()
+            .collect::<Vec<_>>();
+        while let Some(idx) = stack.pop() {
+            order.push(idx);
+            stack.extend(children[idx].iter().rev());
+        }
+        let mut new_indices = vec![0; joints.len()];
+        for (new_idx, idx) in order.iter().enumerate() {
+            new_indices[*idx] = new_idx;
+        }
+
+        // Index of the joints of the skin, indexed by joint name
+        let mut skin_indices = HashMap::new();
+        for (bind_data_joint_idx, joint_name) in bind_data.joint_names.iter().enumerate() {
+            let id = resolve_skin_joint(&nodes, joint_name)
+                .filter(|id| joint_indices.contains_key(*id))
+                .ok_or_else(|| Error::JointNotMatched {
+                    name: joint_name.clone(),
+                })?;
+            skin_indices.insert(id.to_owned(), bind_data_joint_idx);
+        }
+
+        let mut s = Skeleton::new();
+        for (joint_idx, idx) in order.into_iter().enumerate() {
+            let (name, _, rest_local_transform) = &joints[idx];
+            let parent_idx = parents[idx].map(|parent| new_indices[parent]);
 
-            //}
+            let bind_data_joint_idx = skin_indices.get(name).copied();
+            let mut vertices_attached = false;
+            let mut idx_transform = None;
+            let inverse_bind_pose = if let Some(bind_data_joint_idx) = bind_data_joint_idx {
+                vertices_attached = true;
+                idx_transform = Some(bind_data_joint_idx);
+
+                Some(to_matrix4(
+                    &bind_data.inverse_bind_poses[bind_data_joint_idx],
+                ))
             } else {
+                // Computed from the rest pose when added to the skeleton
                 None
-            }
-        } else {
-            None
+            };
+
+            let bone = Bone::new(
+                joint_idx,
+                parent_idx,
+                *rest_local_transform,
+                inverse_bind_pose,
+                vertices_attached,
+                idx_transform,
+            );
+            s.add(name.to_string(), bone);
         }
+
+        Ok(Some(s))
     }
 
-    fn add(&mut self, name: String, bone: Bone) {
+    fn add(&mut self, name: String, mut bone: Bone) {
+        assert_eq!(bone.name_idx, self.bones.len());
         if let Some(parent_name_idx) = bone.parent_name_idx {
             // By construction, the parent is already in the skeleton
             // Let's check that
-            assert!(self.contains(parent_name_idx));
-            self.root.as_mut().unwrap().add(&bone);
-        } else {
-            // Bone is the root
-            // Make sure there is no root present
-            assert!(self.root.is_none());
+            assert!(parent_name_idx < self.bones.len());
+            let parent = &mut self.bones[parent_name_idx];
+            parent.children.push(bone.name_idx);
+
+            bone.rest_transform = parent.rest_transform * bone.rest_local_transform;
+        }
 
-            self.root = Some(bone);
+        if !bone.vertices_attached {
+            // The bones without vertices have no inverse bind pose in the
+            // bind data. It is retrieved from the rest pose
+            bone.inverse_bind_pose = bone
+                .rest_transform
+                .try_inverse()
+                .unwrap_or_else(Matrix4::identity);
         }
+
+        self.bones.push(bone);
         self.joint_names.push(name);
     }
 
@@ -131,40 +193,323 @@ impl Skeleton {
         &self.joint_names
     }
 
-    fn contains(&self, name_idx: usize) -> bool {
-        if let Some(root) = &self.root {
-            root.contains(name_idx)
-        } else {
-            // The skeleton is empty
-            // hence it contains nothing
-            false
+    /// The bones of the skeleton, parents are stored before their children
+    pub fn get_bones(&self) -> &[Bone] {
+        &self.bones
+    }
+
+    /// Tree view of the skeleton starting from its first root
+    pub fn get_root(&self) -> Option<BoneNode<'_>> {
+        self.get_roots().next()
+    }
+
+    /// Tree views of the skeleton starting from each of its roots
+    pub fn get_roots(&self) -> impl Iterator<Item = BoneNode<'_>> {
+        self.bones
+            .iter()
+            .filter(|b| b.parent_name_idx.is_none())
+            .map(move |b| BoneNode {
+                skeleton: self,
+                idx: b.name_idx,
+            })
+    }
+
+    /// Find a bone by its name
+    pub fn find_bone(&self, name: &str) -> Option<BoneId> {
+        self.joint_names.iter().position(|n| n == name).map(BoneId)
+    }
+
+    pub fn get_bone(&self, id: BoneId) -> &Bone {
+        &self.bones[id.0]
+    }
+
+    pub fn get_bone_name(&self, id: BoneId) -> &str {
+        &self.joint_names[id.0]
+    }
+
+    pub fn parent(&self, id: BoneId) -> Option<BoneId> {
+        self.bones[id.0].parent_name_idx.map(BoneId)
+    }
+
+    pub fn children(&self, id: BoneId) -> impl Iterator<Item = BoneId> + '_ {
+        self.bones[id.0].children.iter().map(|&idx| BoneId(idx))
+    }
+
+    /// Iterate over the ancestors of a bone, from its parent up to the root
+    pub fn ancestors(&self, id: BoneId) -> impl Iterator<Item = BoneId> + '_ {
+        std::iter::successors(self.parent(id), move |&id| self.parent(id))
+    }
+
+    /// Iterate over the bones in depth-first order, root after root
+    pub fn depth_first(&self) -> DepthFirst<'_> {
+        let mut stack = self.get_roots().map(|root| root.idx).collect::<Vec<_>>();
+        stack.reverse();
+        DepthFirst {
+            skeleton: self,
+            stack,
+        }
+    }
+
+    /// Iterate over the bones in breadth-first order
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        let queue = self.get_roots().map(|root| root.idx).collect();
+        BreadthFirst {
+            skeleton: self,
+            queue,
+        }
+    }
+
+    /// Iterate in depth-first order over a bone and all the bones below it
+    pub fn descendants(&self, id: BoneId) -> DepthFirst<'_> {
+        DepthFirst {
+            skeleton: self,
+            stack: vec![id.0],
         }
     }
 
-    pub fn get_root(&self) -> &Option<Bone> {
-        &self.root
+    /// The index of the skinning matrix of a bone, i.e. the index used by the
+    /// `bone_ids` of the vertices. None if no vertices are attached to the bone
+    pub fn skinning_idx(&self, id: BoneId) -> Option<usize> {
+        self.bones[id.0].idx_transform
+    }
+
+    /// The bone corresponding to a skinning matrix index
+    pub fn bone_from_skinning_idx(&self, idx_transform: usize) -> Option<BoneId> {
+        self.bones
+            .iter()
+            .position(|b| b.idx_transform == Some(idx_transform))
+            .map(BoneId)
     }
 
     pub fn get_num_vertices_attached_bones(&self) -> usize {
-        let mut num_bones = 0;
-        if let Some(root) = &self.root {
-            root.get_num_vertices_attached_bones(&mut num_bones);
+        self.bones.iter().filter(|b| b.vertices_attached).count()
+    }
+
+    /// Parent joint index of each joint, indexed by joint index
+    pub(crate) fn get_parent_indices(&self) -> Vec<Option<usize>> {
+        self.bones.iter().map(|b| b.parent_name_idx).collect()
+    }
+
+    /// Local transforms of the joints in the rest pose, indexed by joint index
+    pub fn get_rest_local_transforms(&self) -> Vec<Matrix4<f32>> {
+        self.bones.iter().map(|b| b.rest_local_transform).collect()
+    }
+
+    /// The length of a bone, i.e. the distance to its parent in the rest pose
+    pub fn get_bone_length(&self, id: BoneId) -> f32 {
+        let t = &self.bones[id.0].rest_local_transform;
+        Vector3::new(t[(0, 3)], t[(1, 3)], t[(2, 3)]).norm()
+    }
+
+    /// The skinning matrices of the rest pose, indexed by skinning index
+    ///
+    /// These are the matrices to send to the GPU to display the
+    /// model in its rest pose (e.g. a T-pose)
+    pub fn bind_pose(&self) -> Vec<Matrix4<f32>> {
+        let mut transforms = vec![Matrix4::identity(); self.get_num_vertices_attached_bones()];
+        for bone in &self.bones {
+            if let Some(idx_transform) = bone.idx_transform {
+                transforms[idx_transform] = bone.rest_transform * bone.inverse_bind_pose;
+            }
         }
-        num_bones
+
+        transforms
     }
+
+    /// Build the skeleton made of the kept bones, indexed by joint index
+    ///
+    /// The rest transforms of the removed bones are collapsed into their kept
+    /// descendants, which are attached to their closest kept ancestor.
+    /// `skinning_indices` gives the new skinning index of each old skinning index.
+    /// Returns the new skeleton and the new index of each old bone.
+    pub(crate) fn prune(
+        &self,
+        keep: &[bool],
+        skinning_indices: &[Option<usize>],
+    ) -> (Skeleton, Vec<Option<usize>>) {
+        let mut s = Skeleton::new();
+        let mut new_indices = vec![None; self.bones.len()];
+        for (idx, bone) in self.bones.iter().enumerate() {
+            if !keep[idx] {
+                continue;
+            }
+
+            let mut rest_local_transform = bone.rest_local_transform;
+            let mut parent_idx = None;
+            for ancestor in self.ancestors(BoneId(idx)) {
+                if let Some(new_idx) = new_indices[ancestor.0] {
+                    parent_idx = Some(new_idx);
+                    break;
+                }
+                rest_local_transform =
+                    self.bones[ancestor.0].rest_local_transform * rest_local_transform;
+            }
+
+            let idx_transform = bone
+                .idx_transform
+                .and_then(|idx_transform| skinning_indices[idx_transform]);
+            let inverse_bind_pose = idx_transform.map(|_| bone.inverse_bind_pose);
+
+            let new_idx = s.bones.len();
+            new_indices[idx] = Some(new_idx);
+            let bone = Bone::new(
+                new_idx,
+                parent_idx,
+                rest_local_transform,
+                inverse_bind_pose,
+                idx_transform.is_some(),
+                idx_transform,
+            );
+            s.add(self.joint_names[idx].clone(), bone);
+        }
+
+        (s, new_indices)
+    }
+}
+/// A joint node of the visual scene
+struct JointNode {
+    id: String,
+    sid: Option<String>,
+    parent: Option<String>,
+    // Whether the node belongs to one of the skeletons of the skin controllers
+    in_skeleton: bool,
+}
+
+/// Retrieve the ids of the root nodes given to the skin controllers
+fn skeleton_roots(element: &xml::Element) -> Vec<String> {
+    let mut roots = vec![];
+    for child in &element.children {
+        if let xml::Xml::ElementNode(child) = child {
+            if child.name == "skeleton" {
+                let root = child.content_str();
+                roots.push(root.trim().trim_start_matches('#').to_owned());
+            } else {
+                roots.extend(skeleton_roots(child));
+            }
+        }
+    }
+
+    roots
+}
+
+/// Retrieve the joint nodes with their parent joint, parents first
+fn joint_nodes(
+    element: &xml::Element,
+    parent: Option<&str>,
+    in_skeleton: bool,
+    skeleton_roots: &[String],
+    nodes: &mut Vec<JointNode>,
+) {
+    for child in &element.children {
+        if let xml::Xml::ElementNode(child) = child {
+            let mut child_parent = parent;
+            let mut child_in_skeleton = in_skeleton;
+            if child.name == "node" {
+                let id = child.get_attribute("id", None);
+                if let Some(id) = id {
+                    child_in_skeleton |= skeleton_roots.iter().any(|root| root == id);
+                }
+
+                if child.get_attribute("type", None) == Some("JOINT") {
+                    if let Some(id) = id {
+                        nodes.push(JointNode {
+                            id: id.to_owned(),
+                            sid: child.get_attribute("sid", None).map(|sid| sid.to_owned()),
+                            parent: parent.map(|p| p.to_owned()),
+                            in_skeleton: child_in_skeleton,
+                        });
+                        child_parent = Some(id);
+                    }
+                }
+            }
+
+            joint_nodes(
+                child,
+                child_parent,
+                child_in_skeleton,
+                skeleton_roots,
+                nodes,
+            );
+        }
+    }
+}
+
+/// Find the id of the joint node referenced by the skin
+///
+/// The skin refers to its joints either by id or by sid,
+/// the sids being looked for in the skeletons of the controller
+fn resolve_skin_joint<'a>(nodes: &'a [JointNode], joint_name: &str) -> Option<&'a str> {
+    nodes
+        .iter()
+        .find(|node| node.id == joint_name)
+        .or_else(|| {
+            nodes
+                .iter()
+                .find(|node| node.in_skeleton && node.sid.as_deref() == Some(joint_name))
+        })
+        .map(|node| node.id.as_str())
 }
-use na::Matrix4;
+
+/// The index of a bone in its skeleton
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BoneId(pub usize);
+
+/// Depth-first iterator over the bones of a skeleton
+pub struct DepthFirst<'a> {
+    skeleton: &'a Skeleton,
+    stack: Vec<usize>,
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = BoneId;
+
+    fn next(&mut self) -> Option<BoneId> {
+        let idx = self.stack.pop()?;
+        // Push the children in reverse order so that
+        // the first child is visited first
+        self.stack
+            .extend(self.skeleton.bones[idx].children.iter().rev());
+
+        Some(BoneId(idx))
+    }
+}
+
+/// Breadth-first iterator over the bones of a skeleton
+pub struct BreadthFirst<'a> {
+    skeleton: &'a Skeleton,
+    queue: VecDeque<usize>,
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = BoneId;
+
+    fn next(&mut self) -> Option<BoneId> {
+        let idx = self.queue.pop_front()?;
+        self.queue.extend(self.skeleton.bones[idx].children.iter());
+
+        Some(BoneId(idx))
+    }
+}
+
+use na::{Matrix4, Vector3};
+use std::collections::{HashMap, VecDeque};
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Bone {
-    // The name of the bone
+    // The name of the bone, also the index of the bone in the skeleton
     name_idx: usize,
     /// The name of its parent, None if the bone is the root
     parent_name_idx: Option<usize>,
 
-    /// Node's children if there are some
-    children: Option<Vec<Bone>>,
+    /// Indices of the node's children
+    children: Vec<usize>,
     /// The inverse going from the bind pose global space to the local bone space
     inverse_bind_pose: Matrix4<f32>,
+    /// The transform relative to its parent in the rest pose
+    rest_local_transform: Matrix4<f32>,
+    /// The global transform in the rest pose, composed from the rest local
+    /// transforms. It may differ from the bind pose, which is the inverse of
+    /// `inverse_bind_pose`
+    rest_transform: Matrix4<f32>,
     // A flag telling if some vertices are attached to it
     // This flag will determine if the bone needs to be sent
     // to the GPU or not
@@ -176,58 +521,54 @@ impl Bone {
     fn new(
         name_idx: usize,
         parent_name_idx: Option<usize>,
-        inverse_bind_pose: Matrix4<f32>,
+        rest_local_transform: Matrix4<f32>,
+        inverse_bind_pose: Option<Matrix4<f32>>,
         vertices_attached: bool,
         idx_transform: Option<usize>,
     ) -> Self {
         Bone {
             name_idx,
             parent_name_idx,
-            children: None,
-            inverse_bind_pose,
+            children: vec![],
+            inverse_bind_pose: inverse_bind_pose.unwrap_or_else(Matrix4::identity),
+            rest_local_transform,
+            // Global transform of a root bone
+            rest_transform: rest_local_transform,
             vertices_attached,
             idx_transform,
         }
     }
 
-    pub fn add(&mut self, bone: &Bone) {
-        if bone.parent_name_idx.unwrap() == self.name_idx {
-            // We append bone to the children
-            let bone = bone.clone();
-            if let Some(children) = &mut self.children {
-                children.push(bone);
-            } else {
-                self.children = Some(vec![bone]);
-            }
-        } else if let Some(children) = &mut self.children {
-            for child in children.iter_mut() {
-                child.add(bone);
-            }
-        }
+    pub fn get_inverse_bind_pose(&self) -> &Matrix4<f32> {
+        &self.inverse_bind_pose
     }
 
-    pub fn contains(&self, name_idx: usize) -> bool {
-        if self.name_idx == name_idx {
-            true
-        } else if let Some(children) = &self.children {
-            for child in children {
-                if child.contains(name_idx) {
-                    return true;
-                }
-            }
+    pub fn get_rest_local_transform(&self) -> &Matrix4<f32> {
+        &self.rest_local_transform
+    }
 
-            false
-        } else {
-            false
-        }
+    /// The global transform in the rest pose (not the bind pose)
+    pub fn get_rest_transform(&self) -> &Matrix4<f32> {
+        &self.rest_transform
     }
 
-    pub fn get_inverse_bind_pose(&self) -> &Matrix4<f32> {
-        &self.inverse_bind_pose
+    /// The global transform in the bind pose, the inverse of `inverse_bind_pose`
+    pub fn get_bind_transform(&self) -> Matrix4<f32> {
+        self.inverse_bind_pose
+            .try_inverse()
+            .unwrap_or_else(Matrix4::identity)
+    }
+
+    pub fn get_name_idx(&self) -> usize {
+        self.name_idx
+    }
+
+    pub fn get_parent_idx(&self) -> Option<usize> {
+        self.parent_name_idx
     }
 
-    pub fn get_children(&self) -> Option<&Vec<Bone>> {
-        self.children.as_ref()
+    pub fn get_children_idx(&self) -> &[usize] {
+        &self.children
     }
 
     pub fn get_name<'a>(&self, skeleton: &'a Skeleton) -> &'a str {
@@ -237,18 +578,49 @@ impl Bone {
     pub fn has_vertices_attached(&self) -> bool {
         self.vertices_attached
     }
+}
 
-    pub fn get_num_vertices_attached_bones(&self, num_bones: &mut usize) {
-        *num_bones = if self.vertices_attached {
-            *num_bones + 1
-        } else {
-            *num_bones
-        };
+/// A bone seen from the hierarchy of its skeleton
+#[derive(Debug, Clone, Copy)]
+pub struct BoneNode<'a> {
+    skeleton: &'a Skeleton,
+    idx: usize,
+}
 
-        if let Some(children) = &self.children {
-            for child in children {
-                child.get_num_vertices_attached_bones(num_bones);
-            }
-        }
+impl<'a> BoneNode<'a> {
+    pub fn get_id(&self) -> BoneId {
+        BoneId(self.idx)
+    }
+
+    pub fn get_bone(&self) -> &'a Bone {
+        &self.skeleton.bones[self.idx]
+    }
+
+    pub fn get_name(&self) -> &'a str {
+        &self.skeleton.joint_names[self.idx]
+    }
+
+    pub fn get_parent(&self) -> Option<BoneNode<'a>> {
+        let skeleton = self.skeleton;
+        self.get_bone()
+            .parent_name_idx
+            .map(|idx| BoneNode { skeleton, idx })
+    }
+
+    pub fn get_children(&self) -> impl Iterator<Item = BoneNode<'a>> + 'a {
+        let skeleton = self.skeleton;
+        self.get_bone()
+            .children
+            .iter()
+            .map(move |&idx| BoneNode { skeleton, idx })
+    }
+}
+
+use std::ops::Deref;
+impl<'a> Deref for BoneNode<'a> {
+    type Target = Bone;
+
+    fn deref(&self) -> &Bone {
+        self.get_bone()
     }
 }
@@ -0,0 +1,467 @@
+use super::animation::Animations;
+use super::blend::{BlendSpace1D, BlendSpace2D};
+use super::pose::Pose;
+use super::Error;
+
+use na::{Matrix4, Point2};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What a state plays
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Motion {
+    /// A clip of `Animations`
+    Clip(String),
+    /// A blend space driven by a float parameter
+    BlendSpace1D {
+        blend_space: BlendSpace1D,
+        parameter: String,
+    },
+    /// A blend space driven by two float parameters
+    BlendSpace2D {
+        blend_space: BlendSpace2D,
+        parameter_x: String,
+        parameter_y: String,
+    },
+}
+
+impl Motion {
+    /// The duration of a cycle of the motion
+    fn get_duration(
+        &self,
+        animations: &Animations,
+        parameters: &HashMap<String, Parameter>,
+    ) -> Result<f32, Error> {
+        match self {
+            Motion::Clip(name) => Ok(animations.get_clip_or_err(name)?.get_duration()),
+            Motion::BlendSpace1D {
+                blend_space,
+                parameter,
+            } => blend_space.get_duration(animations, get_float(parameters, parameter)),
+            Motion::BlendSpace2D {
+                blend_space,
+                parameter_x,
+                parameter_y,
+            } => {
+                let parameter = Point2::new(
+                    get_float(parameters, parameter_x),
+                    get_float(parameters, parameter_y),
+                );
+                blend_space.get_duration(animations, &parameter)
+            }
+        }
+    }
+
+    /// The pose of the motion at a normalized time
+    ///
+    /// The motion loops whatever the wrap mode of its clips, the normalized
+    /// time of a state keeps growing past 1.0 over the cycles.
+    fn query_pose(
+        &self,
+        animations: &Animations,
+        parameters: &HashMap<String, Parameter>,
+        normalized_time: f32,
+    ) -> Result<Pose, Error> {
+        let normalized_time = normalized_time.rem_euclid(1.0);
+        match self {
+            Motion::Clip(name) => {
+                let anim = animations.get_clip_or_err(name)?;
+                Ok(anim.query_pose(normalized_time * anim.get_duration()))
+            }
+            Motion::BlendSpace1D {
+                blend_space,
+                parameter,
+            } => blend_space.query_pose(
+                animations,
+                get_float(parameters, parameter),
+                normalized_time,
+            ),
+            Motion::BlendSpace2D {
+                blend_space,
+                parameter_x,
+                parameter_y,
+            } => {
+                let parameter = Point2::new(
+                    get_float(parameters, parameter_x),
+                    get_float(parameters, parameter_y),
+                );
+                blend_space.query_pose(animations, &parameter, normalized_time)
+            }
+        }
+    }
+}
+
+/// A state of a state machine
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub name: String,
+    pub motion: Motion,
+    /// Playback speed of the motion, 1.0 by default
+    pub speed: f32,
+}
+
+impl State {
+    pub fn new(name: &str, motion: Motion) -> Self {
+        State {
+            name: name.to_owned(),
+            motion,
+            speed: 1.0,
+        }
+    }
+}
+
+/// A user parameter the conditions of the transitions are evaluated on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Parameter {
+    Float(f32),
+    Bool(bool),
+    /// A boolean reset once a transition using it is taken
+    Trigger(bool),
+}
+
+/// A test on a user parameter, a missing parameter being 0.0 or false
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    Greater {
+        parameter: String,
+        value: f32,
+    },
+    Less {
+        parameter: String,
+        value: f32,
+    },
+    IsTrue(String),
+    IsFalse(String),
+    /// The trigger has been set since the last transition using it
+    Triggered(String),
+}
+
+impl Condition {
+    fn is_satisfied(&self, parameters: &HashMap<String, Parameter>) -> bool {
+        match self {
+            Condition::Greater { parameter, value } => get_float(parameters, parameter) > *value,
+            Condition::Less { parameter, value } => get_float(parameters, parameter) < *value,
+            Condition::IsTrue(parameter) | Condition::Triggered(parameter) => {
+                get_bool(parameters, parameter)
+            }
+            Condition::IsFalse(parameter) => !get_bool(parameters, parameter),
+        }
+    }
+}
+
+/// A transition between two states, taken when all its conditions are satisfied
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    /// The state the transition starts from, None for any other state
+    pub from: Option<String>,
+    pub to: String,
+    pub conditions: Vec<Condition>,
+    /// The normalized time at which the source state can be left, e.g. 1.0 to
+    /// let a clip finish. The transition is only taken when the time of the state
+    /// crosses it, then at the same point of each following cycle, e.g. 1.5, 2.5...
+    /// for 0.5. None to leave the state at any time.
+    pub exit_time: Option<f32>,
+    /// The duration of the crossfade towards the target state
+    pub duration: f32,
+}
+
+impl Transition {
+    /// A transition without conditions nor exit time
+    pub fn new(from: Option<&str>, to: &str, duration: f32) -> Self {
+        Transition {
+            from: from.map(|from| from.to_owned()),
+            to: to.to_owned(),
+            conditions: vec![],
+            exit_time: None,
+            duration,
+        }
+    }
+}
+
+/// The state being faded out during a transition
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Fade {
+    from: usize,
+    normalized_time: f32,
+    elapsed: f32,
+    duration: f32,
+}
+
+/// A data-driven animation state machine
+///
+/// The states play clips or blend spaces, and the transitions between them are
+/// taken according to user parameters. The machine is advanced every frame with
+/// `update`, which crossfades between the states when taking a transition.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateMachine {
+    states: Vec<State>,
+    transitions: Vec<Transition>,
+    parameters: HashMap<String, Parameter>,
+    // The index of the current state
+    current: usize,
+    // Normalized time since entering the current state
+    normalized_time: f32,
+    // Normalized time of the current state before the last advance
+    previous_normalized_time: f32,
+    fade: Option<Fade>,
+}
+
+impl StateMachine {
+    /// A state machine starting in its entry state
+    pub fn new(entry: State) -> Self {
+        StateMachine {
+            states: vec![entry],
+            transitions: vec![],
+            parameters: HashMap::new(),
+            current: 0,
+            normalized_time: 0.0,
+            previous_normalized_time: 0.0,
+            fade: None,
+        }
+    }
+
+    pub fn add_state(&mut self, state: State) {
+        self.states.push(state);
+    }
+
+    /// Add a transition, its states have to be added first
+    ///
+    /// The transitions are checked in the order they have been added
+    pub fn add_transition(&mut self, transition: Transition) -> Result<(), Error> {
+        for name in transition
+            .from
+            .iter()
+            .chain(std::iter::once(&transition.to))
+        {
+            self.find_state(name)?;
+        }
+        self.transitions.push(transition);
+
+        Ok(())
+    }
+
+    pub fn set_float(&mut self, name: &str, value: f32) {
+        self.parameters
+            .insert(name.to_owned(), Parameter::Float(value));
+    }
+
+    pub fn set_bool(&mut self, name: &str, value: bool) {
+        self.parameters
+            .insert(name.to_owned(), Parameter::Bool(value));
+    }
+
+    pub fn set_trigger(&mut self, name: &str) {
+        self.parameters
+            .insert(name.to_owned(), Parameter::Trigger(true));
+    }
+
+    pub fn get_parameter(&self, name: &str) -> Option<Parameter> {
+        self.parameters.get(name).copied()
+    }
+
+    pub fn get_states(&self) -> &[State] {
+        &self.states
+    }
+
+    pub fn get_transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    /// The name of the current state, i.e. the target state during a transition
+    pub fn get_current_state(&self) -> &str {
+        &self.states[self.current].name
+    }
+
+    /// The normalized time since entering the current state,
+    /// going past 1.0 when the motion loops
+    pub fn get_normalized_time(&self) -> f32 {
+        self.normalized_time
+    }
+
+    pub fn is_in_transition(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    /// Jump to a state without crossfade
+    pub fn set_state(&mut self, name: &str) -> Result<(), Error> {
+        self.current = self.find_state(name)?;
+        self.normalized_time = 0.0;
+        self.previous_normalized_time = 0.0;
+        self.fade = None;
+
+        Ok(())
+    }
+
+    /// Advance the state machine in time
+    ///
+    /// The first transition whose conditions and exit time are satisfied is
+    /// taken, unless a transition is already in progress
+    pub fn advance(&mut self, animations: &Animations, dt: f32) -> Result<(), Error> {
+        self.previous_normalized_time = self.normalized_time;
+        self.normalized_time =
+            self.advance_state(animations, self.current, self.normalized_time, dt)?;
+
+        if let Some(fade) = &self.fade {
+            let fade = Fade {
+                normalized_time: self.advance_state(
+                    animations,
+                    fade.from,
+                    fade.normalized_time,
+                    dt,
+                )?,
+                elapsed: fade.elapsed + dt,
+                ..fade.clone()
+            };
+            self.fade = if fade.elapsed < fade.duration {
+                Some(fade)
+            } else {
+                None
+            };
+        }
+
+        if self.fade.is_none() {
+            if let Some(transition) = self.find_transition() {
+                self.take_transition(transition)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Query the pose of the current state, crossfaded with the
+    /// previous state during a transition
+    pub fn query_pose(&self, animations: &Animations) -> Result<Pose, Error> {
+        let pose = self.states[self.current].motion.query_pose(
+            animations,
+            &self.parameters,
+            self.normalized_time,
+        )?;
+
+        if let Some(fade) = &self.fade {
+            let from_pose = self.states[fade.from].motion.query_pose(
+                animations,
+                &self.parameters,
+                fade.normalized_time,
+            )?;
+            Ok(from_pose.blend(&pose, fade.elapsed / fade.duration))
+        } else {
+            Ok(pose)
+        }
+    }
+
+    /// Advance the state machine then write the skinning matrices of its pose
+    pub fn update(
+        &mut self,
+        animations: &Animations,
+        dt: f32,
+        transforms: &mut Vec<Matrix4<f32>>,
+    ) -> Result<(), Error> {
+        self.advance(animations, dt)?;
+        let pose = self.query_pose(animations)?;
+        pose.write_skinning_transforms(animations.get_skeleton(), transforms);
+
+        Ok(())
+    }
+
+    fn find_state(&self, name: &str) -> Result<usize, Error> {
+        self.states
+            .iter()
+            .position(|state| state.name == name)
+            .ok_or_else(|| Error::StateNotFound {
+                name: name.to_owned(),
+            })
+    }
+
+    /// The new normalized time of a state after `dt` seconds
+    fn advance_state(
+        &self,
+        animations: &Animations,
+        idx: usize,
+        normalized_time: f32,
+        dt: f32,
+    ) -> Result<f32, Error> {
+        let state = &self.states[idx];
+        let duration = state.motion.get_duration(animations, &self.parameters)?;
+        if duration > 0.0 {
+            Ok(normalized_time + dt * state.speed / duration)
+        } else {
+            Ok(normalized_time)
+        }
+    }
+
+    /// The index of the first transition that can be taken
+    fn find_transition(&self) -> Option<usize> {
+        let current = self.get_current_state();
+        self.transitions.iter().position(|transition| {
+            let from_current = match &transition.from {
+                Some(from) => from == current,
+                None => transition.to != current,
+            };
+            let exit_time_reached = transition
+                .exit_time
+                .map(|exit_time| self.exit_time_crossed(exit_time))
+                != Some(false);
+
+            from_current
+                && exit_time_reached
+                && transition
+                    .conditions
+                    .iter()
+                    .all(|condition| condition.is_satisfied(&self.parameters))
+        })
+    }
+
+    /// Whether the last advance crossed the exit time in one of the cycles
+    fn exit_time_crossed(&self, exit_time: f32) -> bool {
+        // The first time of the form `exit_time + n` at or after the previous time
+        let cycle = (self.previous_normalized_time - exit_time).ceil().max(0.0);
+        let time = exit_time + cycle;
+
+        self.previous_normalized_time <= time && time < self.normalized_time
+    }
+
+    fn take_transition(&mut self, idx: usize) -> Result<(), Error> {
+        let transition = &self.transitions[idx];
+        let to = self.find_state(&transition.to)?;
+
+        // The triggers are consumed by the transition
+        for condition in &transition.conditions {
+            if let Condition::Triggered(name) = condition {
+                self.parameters
+                    .insert(name.clone(), Parameter::Trigger(false));
+            }
+        }
+
+        self.fade = if transition.duration > 0.0 {
+            Some(Fade {
+                from: self.current,
+                normalized_time: self.normalized_time,
+                elapsed: 0.0,
+                duration: transition.duration,
+            })
+        } else {
+            None
+        };
+        self.current = to;
+        self.normalized_time = 0.0;
+        self.previous_normalized_time = 0.0;
+
+        Ok(())
+    }
+}
+
+fn get_float(parameters: &HashMap<String, Parameter>, name: &str) -> f32 {
+    match parameters.get(name) {
+        Some(Parameter::Float(value)) => *value,
+        Some(Parameter::Bool(true)) | Some(Parameter::Trigger(true)) => 1.0,
+        Some(_) | None => 0.0,
+    }
+}
+
+fn get_bool(parameters: &HashMap<String, Parameter>, name: &str) -> bool {
+    match parameters.get(name) {
+        Some(Parameter::Float(value)) => *value != 0.0,
+        Some(Parameter::Bool(value)) | Some(Parameter::Trigger(value)) => *value,
+        None => false,
+    }
+}
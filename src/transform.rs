@@ -1,12 +1,18 @@
-use na::{Matrix4, Quaternion, UnitQuaternion, Vector3};
-#[derive(PartialEq, Clone, Debug)]
+use na::{Isometry3, Matrix4, Quaternion, Translation3, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Transform {
     // Translation vector
     t: Vector3<f32>,
     // Rotating component
     r: UnitQuaternion<f32>,
+    // Scale factors along the local axes
+    s: Vector3<f32>,
 }
 
+/// Scale under which an axis is considered degenerated
+const MIN_SCALE: f32 = 1e-6;
+
 // Code adapted to Rust from: https://www.euclideanspace.com/maths/geometry/rotations/conversions/matrixToQuaternion/
 fn quat_from_mat4(m: &Matrix4<f32>) -> UnitQuaternion<f32> {
     let tr = m[(0, 0)] + m[(1, 1)] + m[(2, 2)];
@@ -49,9 +55,27 @@ fn quat_from_mat4(m: &Matrix4<f32>) -> UnitQuaternion<f32> {
 
 impl From<&Matrix4<f32>> for Transform {
     fn from(m: &Matrix4<f32>) -> Self {
-        let r = quat_from_mat4(m);
         let t = Vector3::<f32>::new(m[(0, 3)], m[(1, 3)], m[(2, 3)]);
-        Transform { t, r }
+
+        // Remove the scale from the axes before retrieving the rotation
+        let axis = |i: usize| Vector3::new(m[(0, i)], m[(1, i)], m[(2, i)]);
+        let (x, y, z) = (axis(0), axis(1), axis(2));
+        let mut s = Vector3::new(x.norm(), y.norm(), z.norm());
+        if x.cross(&y).dot(&z) < 0.0 {
+            // Mirroring
+            s.x = -s.x;
+        }
+        let mut rotation = *m;
+        for (i, scale) in s.iter().enumerate() {
+            if scale.abs() > MIN_SCALE {
+                for j in 0..3 {
+                    rotation[(j, i)] /= scale;
+                }
+            }
+        }
+        let r = quat_from_mat4(&rotation);
+
+        Transform { t, r, s }
     }
 }
 
@@ -59,6 +83,15 @@ pub fn to_matrix4(m: &[[f32; 4]; 4]) -> Matrix4<f32> {
     let m = unsafe { std::slice::from_raw_parts(m.as_ptr() as *const f32, 16) };
     Matrix4::from_row_slice(m)
 }
+pub fn from_matrix4(m: &Matrix4<f32>) -> [[f32; 4]; 4] {
+    let mut a = [[0.0; 4]; 4];
+    for (i, row) in a.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = m[(i, j)];
+        }
+    }
+    a
+}
 impl From<&[[f32; 4]; 4]> for Transform {
     fn from(m: &[[f32; 4]; 4]) -> Self {
         let mat = to_matrix4(m);
@@ -68,11 +101,17 @@ impl From<&[[f32; 4]; 4]> for Transform {
 
 impl From<Transform> for Matrix4<f32> {
     fn from(t: Transform) -> Self {
-        let Transform { t, r } = t;
+        let Transform { t, r, s } = t;
 
         // Convert the quaternion to a matrix
         // describing a pure rotation
         let mut mat: Matrix4<f32> = r.into();
+        // Scale the axes
+        for (i, scale) in s.iter().enumerate() {
+            for j in 0..3 {
+                mat[(j, i)] *= scale;
+            }
+        }
         // Set the translation part
         mat[(0, 3)] = t.x;
         mat[(1, 3)] = t.y;
@@ -82,13 +121,90 @@ impl From<Transform> for Matrix4<f32> {
     }
 }
 
+/// The scale of the transform is dropped
+impl From<&Transform> for Isometry3<f32> {
+    fn from(t: &Transform) -> Self {
+        Isometry3::from_parts(Translation3::from(t.t), t.r)
+    }
+}
+
+impl From<&Isometry3<f32>> for Transform {
+    fn from(i: &Isometry3<f32>) -> Self {
+        Transform {
+            t: i.translation.vector,
+            r: i.rotation,
+            s: Vector3::repeat(1.0),
+        }
+    }
+}
+
 impl Transform {
+    pub fn new(t: Vector3<f32>, r: UnitQuaternion<f32>) -> Self {
+        Transform {
+            t,
+            r,
+            s: Vector3::repeat(1.0),
+        }
+    }
+
+    pub fn from_parts(t: Vector3<f32>, r: UnitQuaternion<f32>, s: Vector3<f32>) -> Self {
+        Transform { t, r, s }
+    }
+
+    pub fn get_translation(&self) -> &Vector3<f32> {
+        &self.t
+    }
+
+    pub fn get_rotation(&self) -> &UnitQuaternion<f32> {
+        &self.r
+    }
+
+    pub fn get_scale(&self) -> &Vector3<f32> {
+        &self.s
+    }
+
+    /// The same transform with another rotation
+    pub fn with_rotation(&self, r: UnitQuaternion<f32>) -> Self {
+        Transform { r, ..self.clone() }
+    }
+
+    /// The transform doing nothing
+    pub fn identity() -> Self {
+        Transform::new(Vector3::zeros(), UnitQuaternion::identity())
+    }
+
+    /// The additive offset of this transform relative to a reference one
+    pub fn difference(&self, reference: &Self) -> Self {
+        let t = self.t - reference.t;
+        let r = reference.r.inverse() * self.r;
+        let s = self.s.zip_map(
+            &reference.s,
+            |s, rs| if rs.abs() > MIN_SCALE { s / rs } else { s },
+        );
+
+        Transform { t, r, s }
+    }
+
+    /// Add an additive offset, scaled by a weight, on top of this transform
+    ///
+    /// The weighted offset is interpolated from the identity
+    /// like the blending between two transforms
+    pub fn add(&self, difference: &Self, weight: f32) -> Self {
+        let difference = Transform::identity().interpolate(difference, weight);
+        let t = self.t + difference.t;
+        let r = self.r * difference.r;
+        let s = self.s.component_mul(&difference.s);
+
+        Transform { t, r, s }
+    }
+
     pub fn interpolate(&self, rhs: &Self, alpha: f32) -> Self {
         // Linear interpolation for the translation part
         let t = self.t.lerp(&rhs.t, alpha);
         // Spherical interpolation between the rotation parts
         let r = self.r.slerp(&rhs.r, alpha);
+        let s = self.s.lerp(&rhs.s, alpha);
 
-        Transform { t, r }
+        Transform { t, r, s }
     }
 }
@@ -6,7 +6,7 @@ type Weight = f32;
 type BoneIdx = i32;
 
 use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct Vertices {
     pub positions: Vec<Position>,
     pub normals: Vec<Normal>,
@@ -20,3 +20,170 @@ pub struct Vertices {
 
     pub indices: Vec<Index>,
 }
+
+use super::Error;
+use na::Matrix4;
+use std::collections::HashMap;
+impl Vertices {
+    /// Skin the vertices on the CPU
+    ///
+    /// `transforms` are the skinning matrices of the bones, e.g. given
+    /// by `Animations::query`. Returns the posed positions and normals.
+    /// The vertices are returned unchanged if they are not attached to bones,
+    /// as are the vertices whose weights are all null.
+    pub fn skin(&self, transforms: &[Matrix4<f32>]) -> (Vec<Position>, Vec<Normal>) {
+        if let (Some(weights), Some(bone_ids)) = (&self.weights, &self.bone_ids) {
+            self.positions
+                .iter()
+                .zip(self.normals.iter())
+                .zip(weights.iter().zip(bone_ids.iter()))
+                .map(|((p, n), (w, b))| {
+                    let m = if w[0] + w[1] > 0.0 {
+                        transforms[b[0] as usize] * w[0] + transforms[b[1] as usize] * w[1]
+                    } else {
+                        // Keep the vertex at its bind position
+                        Matrix4::identity()
+                    };
+
+                    let p = m.transform_point(p);
+                    let n = m.transform_vector(n).normalize();
+                    (p, n)
+                })
+                .unzip()
+        } else {
+            (self.positions.clone(), self.normals.clone())
+        }
+    }
+
+    /// Split the vertices into submeshes whose triangles reference at most `max_bones` bones
+    ///
+    /// This is for shaders whose array of bone matrices is limited. The `bone_ids` of
+    /// each submesh are local indices into its palette, see `SubMesh::palette`.
+    /// Two bones influence each vertex so `max_bones` must be at least 6.
+    pub fn split_palettes(&self, max_bones: usize) -> Result<Vec<SubMesh>, Error> {
+        if max_bones < 6 {
+            return Err(Error::InvalidMaxBones { max_bones });
+        }
+
+        let (weights, bone_ids) =
+            if let (Some(weights), Some(bone_ids)) = (&self.weights, &self.bone_ids) {
+                (weights, bone_ids)
+            } else {
+                // The vertices are not attached to any bone
+                return Ok(vec![SubMesh {
+                    vertices: self.clone(),
+                    palette: vec![],
+                }]);
+            };
+
+        let mut submeshes: Vec<SubMesh> = vec![];
+        // The new index of the vertices of each submesh
+        let mut new_indices: Vec<HashMap<Index, Index>> = vec![];
+        for triangle in self.indices.chunks(3) {
+            // The skinning indices weighting the triangle
+            let mut bones = vec![];
+            for &idx in triangle {
+                let idx = idx as usize;
+                for (bone_id, weight) in bone_ids[idx].iter().zip(weights[idx].iter()) {
+                    let bone_id = *bone_id as usize;
+                    if *weight > 0.0 && !bones.contains(&bone_id) {
+                        bones.push(bone_id);
+                    }
+                }
+            }
+            if bones.is_empty() {
+                // The triangle is not weighted, its vertices still need
+                // a bone in the palette for their local indices to be valid
+                bones.push(bone_ids[triangle[0] as usize][0] as usize);
+            }
+
+            // Fill the first submesh having enough room for the bones of the triangle
+            let num_bones = |submesh: &SubMesh| {
+                submesh.palette.len()
+                    + bones
+                        .iter()
+                        .filter(|bone_id| !submesh.palette.contains(bone_id))
+                        .count()
+            };
+            let submesh_idx = if let Some(submesh_idx) = submeshes
+                .iter()
+                .position(|submesh| num_bones(submesh) <= max_bones)
+            {
+                submesh_idx
+            } else {
+                submeshes.push(SubMesh {
+                    vertices: Vertices {
+                        positions: vec![],
+                        normals: vec![],
+                        texcoords: vec![],
+                        weights: Some(vec![]),
+                        bone_ids: Some(vec![]),
+                        indices: vec![],
+                    },
+                    palette: vec![],
+                });
+                new_indices.push(HashMap::new());
+                submeshes.len() - 1
+            };
+
+            let submesh = &mut submeshes[submesh_idx];
+            for bone_id in bones {
+                if !submesh.palette.contains(&bone_id) {
+                    submesh.palette.push(bone_id);
+                }
+            }
+
+            for &idx in triangle {
+                let palette = &submesh.palette;
+                let vertices = &mut submesh.vertices;
+                let new_idx = *new_indices[submesh_idx].entry(idx).or_insert_with(|| {
+                    let i = idx as usize;
+                    vertices.positions.push(self.positions[i]);
+                    vertices.normals.push(self.normals[i]);
+                    if let Some(texcoord) = self.texcoords.get(i) {
+                        vertices.texcoords.push(*texcoord);
+                    }
+
+                    // The bones without weight point to the first bone of the palette
+                    let mut local_ids = [0; 2];
+                    for (local_id, (bone_id, weight)) in local_ids
+                        .iter_mut()
+                        .zip(bone_ids[i].iter().zip(weights[i].iter()))
+                    {
+                        if *weight > 0.0 {
+                            *local_id = palette
+                                .iter()
+                                .position(|b| *b == *bone_id as usize)
+                                .unwrap() as BoneIdx;
+                        }
+                    }
+                    vertices.weights.as_mut().unwrap().push(weights[i]);
+                    vertices.bone_ids.as_mut().unwrap().push(local_ids);
+
+                    (vertices.positions.len() - 1) as Index
+                });
+                vertices.indices.push(new_idx);
+            }
+        }
+
+        Ok(submeshes)
+    }
+}
+
+/// A part of the vertices drawn with its own palette of bone matrices
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+pub struct SubMesh {
+    /// The vertices, their `bone_ids` being indices into the palette
+    pub vertices: Vertices,
+    /// The skinning index of each bone of the palette
+    pub palette: Vec<usize>,
+}
+
+impl SubMesh {
+    /// Gather the skinning matrices of the palette,
+    /// e.g. from the matrices given by `Animations::query`
+    pub fn fill_palette(&self, transforms: &[Matrix4<f32>], palette: &mut Vec<Matrix4<f32>>) {
+        palette.clear();
+        palette.extend(self.palette.iter().map(|&idx| transforms[idx]));
+    }
+}